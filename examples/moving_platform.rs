@@ -0,0 +1,112 @@
+use avian3d::prelude::*;
+use bevy::prelude::*;
+use bevy_game::*;
+
+/// Moves a kinematic platform back and forth between two points
+#[derive(Component)]
+struct Shuttle {
+    from: Vec3,
+    to: Vec3,
+    speed: f32,
+}
+
+fn main() {
+    App::new()
+        .insert_resource(AmbientLight {
+            color: Color::WHITE,
+            brightness: 2000.0,
+            affects_lightmapped_meshes: false,
+        })
+        .insert_resource(ClearColor(Color::Srgba(Srgba::hex("D4F5F5").unwrap())))
+        .add_plugins(PhysicsPlugins::default())
+        .add_plugins(DefaultPlugins)
+        .add_plugins(FpsControllerPlugin::default())
+        .add_systems(Startup, setup)
+        .add_systems(FixedUpdate, shuttle)
+        .run();
+}
+
+fn setup(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    commands.spawn((
+        DirectionalLight {
+            illuminance: light_consts::lux::FULL_DAYLIGHT,
+            shadows_enabled: true,
+            ..default()
+        },
+        Transform::from_xyz(4.0, 7.0, 5.0).looking_at(Vec3::ZERO, Vec3::Y),
+    ));
+
+    let mut cuboid = |size: Vec3, color: Color| {
+        (
+            Collider::cuboid(size.x, size.y, size.z),
+            Mesh3d(meshes.add(Cuboid::from_size(size))),
+            MeshMaterial3d(materials.add(color)),
+        )
+    };
+
+    commands.spawn((
+        cuboid(Vec3::new(40.0, 1.0, 40.0), Color::srgb(0.5, 0.5, 0.5)),
+        RigidBody::Static,
+        Transform::from_xyz(0.0, -0.5, 0.0),
+    ));
+
+    // Slides from side to side
+    commands.spawn((
+        cuboid(Vec3::new(4.0, 0.5, 4.0), Color::srgb(0.8, 0.3, 0.3)),
+        RigidBody::Kinematic,
+        Transform::from_xyz(-8.0, 0.25, -6.0),
+        Shuttle {
+            from: Vec3::new(-8.0, 0.25, -6.0),
+            to: Vec3::new(8.0, 0.25, -6.0),
+            speed: 4.0,
+        },
+    ));
+
+    // Elevator up to a ledge
+    commands.spawn((
+        cuboid(Vec3::new(3.0, 0.5, 3.0), Color::srgb(0.3, 0.8, 0.3)),
+        RigidBody::Kinematic,
+        Transform::from_xyz(8.0, 0.25, 4.0),
+        Shuttle {
+            from: Vec3::new(8.0, 0.25, 4.0),
+            to: Vec3::new(8.0, 6.25, 4.0),
+            speed: 2.0,
+        },
+    ));
+    commands.spawn((
+        cuboid(Vec3::new(6.0, 6.5, 6.0), Color::srgb(0.6, 0.6, 0.6)),
+        RigidBody::Static,
+        Transform::from_xyz(12.5, 3.25, 4.0),
+    ));
+
+    // Merry-go-round
+    commands.spawn((
+        cuboid(Vec3::new(8.0, 0.5, 8.0), Color::srgb(0.3, 0.3, 0.8)),
+        RigidBody::Kinematic,
+        AngularVelocity(Vec3::Y * 1.0),
+        Transform::from_xyz(-6.0, 0.25, 8.0),
+    ));
+
+    FpsPlayerBundle::new(Vec3::new(0.0, 3.0, 10.0))
+        .with_look(0.0, 0.0)
+        .spawn(&mut commands);
+}
+
+/// Drives the platforms with their velocity rather than by moving their transform, so that the
+/// controller can inherit it
+fn shuttle(mut query: Query<(&Shuttle, &Position, &mut LinearVelocity)>) {
+    for (shuttle, position, mut velocity) in &mut query {
+        let direction = (shuttle.to - shuttle.from).normalize_or_zero();
+        let along = Vec3::dot(position.0 - shuttle.from, direction);
+        let length = shuttle.from.distance(shuttle.to);
+        if along >= length {
+            velocity.0 = -direction * shuttle.speed;
+        } else if along <= 0.0 || velocity.0 == Vec3::ZERO {
+            velocity.0 = direction * shuttle.speed;
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use avian3d::prelude::*;
+use bevy::prelude::*;
+use bevy_game::*;
+use leafwing_input_manager::prelude::*;
+
+/// Marks a player that is driven by [`run_in_circles`] instead of an input device
+#[derive(Component)]
+struct Bot;
+
+fn main() {
+    App::new()
+        .insert_resource(AmbientLight {
+            color: Color::WHITE,
+            brightness: 10000.0,
+            affects_lightmapped_meshes: false,
+        })
+        .add_plugins(PhysicsPlugins::default())
+        .add_plugins(DefaultPlugins)
+        .add_plugins((FpsControllerPlugin::default(), LevelPlugin))
+        .add_systems(Startup, setup)
+        .add_systems(Update, spawn_gamepad_players)
+        .add_systems(FixedUpdate, run_in_circles)
+        .run();
+}
+
+fn setup(mut commands: Commands, assets: Res<AssetServer>) {
+    commands.insert_resource(MainScene {
+        handle: assets.load("playground.glb"),
+        is_loaded: false,
+        level: 0,
+    });
+
+    commands.spawn((
+        DirectionalLight {
+            illuminance: light_consts::lux::FULL_DAYLIGHT,
+            shadows_enabled: true,
+            ..default()
+        },
+        Transform::from_xyz(4.0, 7.0, 5.0).looking_at(Vec3::ZERO, Vec3::Y),
+    ));
+
+    // Played with keyboard and mouse, click to grab the cursor
+    FpsPlayerBundle::new(Vec3::new(0.0, 5.0, 0.0)).spawn(&mut commands);
+
+    // Gets its own viewport but no input map, its input is written by a system instead
+    let bot = FpsPlayerBundle::new(Vec3::new(5.0, 5.0, 0.0)).spawn(&mut commands);
+    commands
+        .entity(bot.logical_entity)
+        .remove_with_requires::<InputMap<FpsActions>>()
+        .insert(Bot);
+}
+
+/// Every gamepad that connects gets a player and viewport of its own
+fn spawn_gamepad_players(mut commands: Commands, gamepads: Query<Entity, Added<Gamepad>>) {
+    for gamepad in &gamepads {
+        FpsPlayerBundle::new(Vec3::new(-5.0, 5.0, 0.0))
+            .with_gamepad(gamepad)
+            .spawn(&mut commands);
+    }
+}
+
+fn run_in_circles(time: Res<Time>, mut query: Query<&mut FpsControllerInput, With<Bot>>) {
+    for mut input in &mut query {
+        input.movement = Vec3::Z;
+        input.yaw = (input.yaw + time.delta_secs()).rem_euclid(std::f32::consts::TAU);
+    }
+}
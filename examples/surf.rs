@@ -0,0 +1,64 @@
+use avian3d::prelude::*;
+use bevy::prelude::*;
+use bevy_game::*;
+use std::f32::consts::FRAC_PI_6;
+
+fn main() {
+    App::new()
+        .insert_resource(AmbientLight {
+            color: Color::WHITE,
+            brightness: 2000.0,
+            affects_lightmapped_meshes: false,
+        })
+        .insert_resource(ClearColor(Color::Srgba(Srgba::hex("D4F5F5").unwrap())))
+        .add_plugins(PhysicsPlugins::default())
+        .add_plugins(DefaultPlugins)
+        .add_plugins(FpsControllerPlugin::default())
+        .add_systems(Startup, setup)
+        .run();
+}
+
+fn setup(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    commands.spawn((
+        DirectionalLight {
+            illuminance: light_consts::lux::FULL_DAYLIGHT,
+            shadows_enabled: true,
+            ..default()
+        },
+        Transform::from_xyz(4.0, 7.0, 5.0).looking_at(Vec3::ZERO, Vec3::Y),
+    ));
+
+    let mut cuboid = |size: Vec3, color: Color| {
+        (
+            Collider::cuboid(size.x, size.y, size.z),
+            Mesh3d(meshes.add(Cuboid::from_size(size))),
+            MeshMaterial3d(materials.add(color)),
+            RigidBody::Static,
+        )
+    };
+
+    commands.spawn((
+        cuboid(Vec3::new(40.0, 1.0, 200.0), Color::srgb(0.5, 0.5, 0.5)),
+        Transform::from_xyz(0.0, -0.5, 0.0),
+    ));
+
+    // Two ramps facing each other, steeper than the traction cutoff so they are surfed rather
+    // than walked on. Strafe into a ramp to glide along it without losing speed.
+    let ramp_size = Vec3::new(1.0, 12.0, 200.0);
+    for side in [-1.0, 1.0] {
+        commands.spawn((
+            cuboid(ramp_size, Color::srgb(0.3, 0.5, 0.8)),
+            Transform::from_xyz(side * 6.0, 4.0, 0.0)
+                .with_rotation(Quat::from_rotation_z(-side * FRAC_PI_6)),
+        ));
+    }
+
+    // Drops onto the left ramp
+    FpsPlayerBundle::new(Vec3::new(-6.0, 8.0, 90.0))
+        .with_look(0.0, 0.0)
+        .spawn(&mut commands);
+}
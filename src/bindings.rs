@@ -0,0 +1,309 @@
+use super::components::*;
+use super::events::*;
+use super::util::write_file;
+use bevy::prelude::*;
+use leafwing_input_manager::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// A key or mouse button that an action can be bound to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Binding {
+    Key(KeyCode),
+    Mouse(MouseButton),
+}
+
+/// The keyboard and mouse bindings of the [`FpsActions`], applied to the input map of every player
+/// that is not bound to a gamepad. Looking around is always bound to the mouse. Gamepads only
+/// drive players given one with [`FpsPlayerBundle::with_gamepad`](crate::FpsPlayerBundle::with_gamepad).
+#[derive(Resource, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Bindings(pub BTreeMap<FpsActions, Vec<Binding>>);
+
+impl Default for Bindings {
+    fn default() -> Self {
+        Self(BTreeMap::from([
+            (FpsActions::Forward, vec![Binding::Key(KeyCode::KeyW)]),
+            (FpsActions::Backward, vec![Binding::Key(KeyCode::KeyS)]),
+            (FpsActions::Left, vec![Binding::Key(KeyCode::KeyA)]),
+            (FpsActions::Right, vec![Binding::Key(KeyCode::KeyD)]),
+            (FpsActions::Sprint, vec![Binding::Key(KeyCode::ShiftLeft)]),
+            (FpsActions::Crouch, vec![Binding::Key(KeyCode::ControlLeft)]),
+            (FpsActions::Jump, vec![Binding::Key(KeyCode::Space)]),
+            (FpsActions::Fly, vec![Binding::Key(KeyCode::AltLeft)]),
+        ]))
+    }
+}
+
+impl Bindings {
+    /// Reads the bindings saved at `path`, falling back to the defaults if there are none yet or
+    /// they can't be read
+    pub fn load(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref();
+        let ron = match fs::read_to_string(path) {
+            Ok(ron) => ron,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Self::default(),
+            Err(error) => {
+                warn!(
+                    "Using the default bindings, can't read {}: {error}",
+                    path.display()
+                );
+                return Self::default();
+            }
+        };
+        ron::from_str(&ron).unwrap_or_else(|error| {
+            warn!(
+                "Using the default bindings, {} is invalid: {error}",
+                path.display()
+            );
+            Self::default()
+        })
+    }
+
+    /// Saves the bindings to `path` as RON that is easy to edit by hand
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let ron = ron::ser::to_string_pretty(self, default()).map_err(io::Error::other)?;
+        write_file(path.as_ref(), ron)
+    }
+
+    /// Binds `action` to `binding` alone, unbinding it from any other actions it was bound to.
+    /// Returns those other actions.
+    pub fn rebind(&mut self, action: FpsActions, binding: Binding) -> Vec<FpsActions> {
+        let mut conflicts = Vec::new();
+        for (&other, bindings) in &mut self.0 {
+            if other != action && bindings.contains(&binding) {
+                bindings.retain(|&other_binding| other_binding != binding);
+                conflicts.push(other);
+            }
+        }
+        self.0.insert(action, vec![binding]);
+        conflicts
+    }
+
+    pub fn input_map(&self) -> InputMap<FpsActions> {
+        let mut input_map = InputMap::default();
+        input_map.insert_dual_axis(FpsActions::MousePosition, MouseMove::default());
+        for (&action, bindings) in &self.0 {
+            for &binding in bindings {
+                match binding {
+                    Binding::Key(key) => input_map.insert(action, key),
+                    Binding::Mouse(button) => input_map.insert(action, button),
+                };
+            }
+        }
+        input_map
+    }
+}
+
+/// Where the [`Bindings`] are saved
+#[derive(Resource, Clone, Debug)]
+pub struct BindingsFile(pub PathBuf);
+
+/// The action waiting for a key or mouse button press to be bound to, after a [`StartRebind`]
+#[derive(Resource, Clone, Copy, Debug, Default)]
+pub struct Rebinding(pub Option<FpsActions>);
+
+/// Binds the action of a [`StartRebind`] to the next key or mouse button pressed, escape cancels
+pub fn listen_for_rebind(
+    mut rebinding: ResMut<Rebinding>,
+    mut start_rebind: EventReader<StartRebind>,
+    keys: Res<ButtonInput<KeyCode>>,
+    mouse_buttons: Res<ButtonInput<MouseButton>>,
+    mut bindings: ResMut<Bindings>,
+    mut rebound: EventWriter<Rebound>,
+) {
+    if let Some(&StartRebind { action }) = start_rebind.read().last() {
+        // Mouse movement and sticks can't be bound to a button
+        if action.input_control_kind() == InputControlKind::Button {
+            rebinding.0 = Some(action);
+        }
+        // Whatever was pressed to start rebinding is not the new binding
+        return;
+    }
+    let Some(action) = rebinding.0 else {
+        return;
+    };
+    if keys.just_pressed(KeyCode::Escape) {
+        rebinding.0 = None;
+        return;
+    }
+    let pressed = keys.get_just_pressed().next().map(|&key| Binding::Key(key));
+    let pressed = pressed.or_else(|| {
+        let button = mouse_buttons.get_just_pressed().next();
+        button.map(|&button| Binding::Mouse(button))
+    });
+    let Some(binding) = pressed else {
+        return;
+    };
+    rebinding.0 = None;
+    let conflicts = bindings.rebind(action, binding);
+    rebound.write(Rebound {
+        action,
+        binding,
+        conflicts,
+    });
+}
+
+/// Saves the bindings whenever they are rebound
+pub fn save_bindings(
+    mut rebound: EventReader<Rebound>,
+    bindings: Res<Bindings>,
+    file: Res<BindingsFile>,
+) {
+    if rebound.read().count() == 0 {
+        return;
+    }
+    if let Err(error) = bindings.save(&file.0) {
+        warn!("Can't save the bindings to {}: {error}", file.0.display());
+    }
+}
+
+/// Applies the bindings to the input map of players that are not bound to a gamepad, when they
+/// change or the player is spawned
+pub fn apply_bindings(
+    bindings: Res<Bindings>,
+    mut input_maps: Query<&mut InputMap<FpsActions>, With<LogicalPlayer>>,
+) {
+    for mut input_map in &mut input_maps {
+        if (bindings.is_changed() || input_map.is_added()) && input_map.gamepad().is_none() {
+            *input_map = bindings.input_map();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BindingsPlugin, FpsPlayerBundle};
+    use bevy::input::{
+        gamepad::{GamepadConnection, GamepadConnectionEvent},
+        InputPlugin,
+    };
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir()
+            .join(format!("bindings-test-{}", std::process::id()))
+            .join(name)
+    }
+
+    #[test]
+    fn saves_and_loads_bindings() {
+        let path = temp_path("saves_and_loads_bindings.ron");
+        assert_eq!(Bindings::load(&path), Bindings::default());
+
+        let mut bindings = Bindings::default();
+        bindings.rebind(FpsActions::Jump, Binding::Mouse(MouseButton::Right));
+        bindings.save(&path).unwrap();
+        assert_eq!(Bindings::load(&path), bindings);
+
+        fs::write(&path, "not bindings").unwrap();
+        assert_eq!(Bindings::load(&path), Bindings::default());
+    }
+
+    #[test]
+    fn rebinds_next_press_and_applies_it() {
+        let path = temp_path("rebinds_next_press_and_applies_it.ron");
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, BindingsPlugin { path: path.clone() }))
+            .init_resource::<ButtonInput<KeyCode>>()
+            .init_resource::<ButtonInput<MouseButton>>();
+        let player = app
+            .world_mut()
+            .spawn((LogicalPlayer, InputMap::<FpsActions>::default()))
+            .id();
+        app.update();
+        assert_eq!(
+            app.world().get::<InputMap<FpsActions>>(player),
+            Some(&Bindings::default().input_map())
+        );
+
+        app.world_mut().send_event(StartRebind {
+            action: FpsActions::Jump,
+        });
+        app.update();
+        assert_eq!(
+            app.world().resource::<Rebinding>().0,
+            Some(FpsActions::Jump)
+        );
+        app.world_mut()
+            .resource_mut::<ButtonInput<KeyCode>>()
+            .press(KeyCode::KeyW);
+        app.update();
+
+        // W moved from walking forward to jumping
+        let world = app.world();
+        assert_eq!(world.resource::<Rebinding>().0, None);
+        let bindings = world.resource::<Bindings>();
+        assert_eq!(bindings.0[&FpsActions::Jump], [Binding::Key(KeyCode::KeyW)]);
+        assert!(bindings.0[&FpsActions::Forward].is_empty());
+        let rebound = world.resource::<Events<Rebound>>();
+        let mut cursor = rebound.get_cursor();
+        let rebound = cursor.read(rebound).last().unwrap();
+        assert_eq!(rebound.conflicts, [FpsActions::Forward]);
+        assert_eq!(
+            world.get::<InputMap<FpsActions>>(player),
+            Some(&bindings.input_map())
+        );
+        assert_eq!(&Bindings::load(&path), bindings);
+    }
+
+    #[test]
+    fn gamepads_only_drive_their_own_player() {
+        let mut app = App::new();
+        app.add_plugins((
+            MinimalPlugins,
+            InputPlugin,
+            InputManagerPlugin::<FpsActions>::default(),
+            BindingsPlugin {
+                path: temp_path("gamepads_only_drive_their_own_player.ron"),
+            },
+        ));
+        let gamepads = [(); 2].map(|_| app.world_mut().spawn_empty().id());
+        for gamepad in gamepads {
+            app.world_mut().send_event(GamepadConnectionEvent {
+                gamepad,
+                connection: GamepadConnection::Connected {
+                    name: "Test gamepad".to_string(),
+                    vendor_id: None,
+                    product_id: None,
+                },
+            });
+        }
+        let keyboard = app.world_mut().spawn(FpsPlayerBundle::new(Vec3::ZERO)).id();
+        let players = gamepads.map(|gamepad| {
+            app.world_mut()
+                .spawn(FpsPlayerBundle::new(Vec3::ZERO).with_gamepad(gamepad))
+                .id()
+        });
+        app.update();
+
+        for (gamepad, owner) in gamepads.into_iter().zip(players) {
+            GamepadButton::South.press_as_gamepad(app.world_mut(), Some(gamepad));
+            GamepadStick::RIGHT.set_axis_pair_as_gamepad(app.world_mut(), Vec2::X, Some(gamepad));
+            app.update();
+
+            for player in [keyboard, players[0], players[1]] {
+                let action_state = app.world().get::<ActionState<FpsActions>>(player).unwrap();
+                let look = action_state.axis_pair(&FpsActions::Look);
+                if player == owner {
+                    assert!(action_state.pressed(&FpsActions::Jump));
+                    assert_eq!(look, Vec2::X);
+                } else {
+                    assert!(!action_state.pressed(&FpsActions::Jump));
+                    assert_eq!(look, Vec2::ZERO);
+                }
+            }
+
+            GamepadButton::South.release_as_gamepad(app.world_mut(), Some(gamepad));
+            GamepadStick::RIGHT.set_axis_pair_as_gamepad(
+                app.world_mut(),
+                Vec2::ZERO,
+                Some(gamepad),
+            );
+            app.update();
+        }
+    }
+}
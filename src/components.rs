@@ -1,21 +1,174 @@
+use avian3d::prelude::LayerMask;
 use bevy::{gltf::Gltf, prelude::*};
 use leafwing_input_manager::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::f32::consts::TAU;
 
-/// A marker component indicating that an entity is on the ground.
+/// A marker component indicating that an entity is on the ground, touching it rather than still
+/// falling towards it.
 #[derive(Component)]
 #[component(storage = "SparseSet")]
 pub struct Grounded;
 
+/// Collision layer of [`Water`] volumes.
+pub const WATER_LAYER: LayerMask = LayerMask(1 << 31);
+/// Collision layer of [`Ladder`] volumes.
+pub const LADDER_LAYER: LayerMask = LayerMask(1 << 30);
+/// Collision layer of [`Checkpoint`] volumes.
+pub const CHECKPOINT_LAYER: LayerMask = LayerMask(1 << 29);
+/// Collision layer of [`KillVolume`]s.
+pub const KILL_LAYER: LayerMask = LayerMask(1 << 28);
+/// Collision layer of [`TriggerVolume`]s and [`LevelExit`]s.
+pub const TRIGGER_LAYER: LayerMask = LayerMask(1 << 27);
+/// Layers of the volumes the controller moves through, its ground and obstacle queries ignore them.
+pub const VOLUME_LAYERS: LayerMask =
+    LayerMask(WATER_LAYER.0 | LADDER_LAYER.0 | CHECKPOINT_LAYER.0 | KILL_LAYER.0 | TRIGGER_LAYER.0);
+
+/// A marker component for sensor volumes the controller swims in. The top of the volume's bounding
+/// box is the water surface.
+#[derive(Component)]
+pub struct Water;
+
+/// A marker component for sensor volumes around ladders, the controller climbs while inside one.
+#[derive(Component)]
+pub struct Ladder;
+
+/// A marker component for sensor volumes that players respawn at once they have touched one. They
+/// respawn at its origin, looking the way it faces.
+#[derive(Component)]
+pub struct Checkpoint;
+
+/// A marker component for sensor volumes that kill players touching them.
+#[derive(Component)]
+pub struct KillVolume;
+
+/// A marker component for sensor volumes without a behavior of their own, e.g. for game logic to
+/// detect players entering an area with [`CollisionStarted`](avian3d::prelude::CollisionStarted).
+#[derive(Component)]
+pub struct TriggerVolume;
+
+/// A marker component for sensor volumes that load the next level of the [`LevelRegistry`] when a
+/// player touches them.
+#[derive(Component)]
+pub struct LevelExit;
+
+/// Where players respawn until they reach a [`Checkpoint`], looking the way it faces. Adding one,
+/// e.g. by loading a level, makes it the respawn point of every player.
+#[derive(Component)]
+pub struct SpawnPoint;
+
+/// Players that fall below this height die.
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct KillPlane(pub f32);
+
+impl Default for KillPlane {
+    fn default() -> Self {
+        Self(-50.0)
+    }
+}
+
+/// Where a player respawns after dying and which way it looks then. Set by [`SpawnPoint`]s and
+/// [`Checkpoint`]s, and back to the origin by default when another level loads.
+#[derive(Component, Clone, Copy, Debug, Default, PartialEq)]
+pub struct RespawnPoint {
+    pub translation: Vec3,
+    pub yaw: f32,
+    pub pitch: f32,
+}
+
+impl RespawnPoint {
+    /// Respawns at the transform's translation, looking along its forward direction
+    pub fn from_transform(transform: &Transform) -> Self {
+        let (yaw, pitch, _) = transform.rotation.to_euler(EulerRot::YXZ);
+        Self {
+            translation: transform.translation,
+            yaw,
+            pitch,
+        }
+    }
+}
+
+/// A marker component indicating that a crouched entity has no room to stand up.
+#[derive(Component)]
+#[component(storage = "SparseSet")]
+pub struct CrouchBlocked;
+
+/// A marker component indicating that an entity is sliding along the ground.
+#[derive(Component)]
+#[component(storage = "SparseSet")]
+pub struct Sliding;
+
+/// A marker component for a [`SceneRoot`] spawned from a level. Once it is spawned, its nodes get
+/// colliders, volumes and spawn points according to their names and glTF extras, see
+/// [`scene_colliders`](crate::scene_colliders).
+#[derive(Component)]
+pub struct LevelScene;
+
+/// A marker component for the text that [`display_text`](crate::display_text) shows the velocity
+/// and position of the player in.
+#[derive(Component)]
+pub struct DebugText;
+
 #[derive(Resource)]
 pub struct MainScene {
     pub handle: Handle<Gltf>,
     pub is_loaded: bool,
+    /// Index of the level in the [`LevelRegistry`]
+    pub level: usize,
+}
+
+/// The levels of the game, in the order that [`LevelExit`]s go through them. Usually read from a
+/// RON file, see [`LevelRegistry::from_ron`].
+#[derive(Resource, Clone, Debug, Default, Deserialize)]
+pub struct LevelRegistry {
+    pub levels: Vec<LevelInfo>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct LevelInfo {
+    pub name: String,
+    /// Asset path of the level's glTF
+    pub path: String,
+}
+
+impl LevelRegistry {
+    /// Parses a registry like `(levels: [(name: "Playground", path: "playground.glb")])`
+    pub fn from_ron(ron: &str) -> Result<Self, ron::error::SpannedError> {
+        ron::from_str(ron)
+    }
 }
 
-#[derive(Actionlike, Clone, Debug, Copy, PartialEq, Eq, Hash, Reflect)]
+/// Whether the current level is still loading. Players are held in place while it is.
+#[derive(States, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum LevelState {
+    #[default]
+    Loading,
+    Playing,
+}
+
+#[derive(
+    Actionlike,
+    Clone,
+    Debug,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    Reflect,
+    Serialize,
+    Deserialize,
+)]
 pub enum FpsActions {
     #[actionlike(DualAxis)]
     MousePosition,
+    /// Analog movement, e.g. on the left stick, added to the four direction buttons
+    #[actionlike(DualAxis)]
+    Move,
+    /// Looking around with a stick, shaped by the player's [`GamepadLook`]
+    #[actionlike(DualAxis)]
+    Look,
     Forward,
     Backward,
     Left,
@@ -26,10 +179,14 @@ pub enum FpsActions {
     Fly,
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum MoveMode {
     Noclip,
     Ground,
+    Swim,
+    Climb,
+    WallRun,
+    Mantle,
 }
 
 #[derive(Component)]
@@ -47,7 +204,7 @@ pub struct CameraConfig {
     pub radius_scale: f32,
 }
 
-#[derive(Component, Default)]
+#[derive(Component, Clone, Copy, Debug, Default, PartialEq)]
 pub struct FpsControllerInput {
     pub fly: bool,
     pub sprint: bool,
@@ -59,9 +216,9 @@ pub struct FpsControllerInput {
 }
 
 #[derive(Component)]
+#[require(FpsControllerState)]
 pub struct FpsController {
     pub move_mode: MoveMode,
-    #[allow(dead_code)]
     pub radius: f32,
     pub gravity: f32,
     pub walk_speed: f32,
@@ -90,10 +247,39 @@ pub struct FpsController {
     pub yaw: f32,
     pub ground_tick: u8,
     pub stop_speed: f32,
-    pub sensitivity: f32,
     pub enable_input: bool,
-    #[allow(dead_code)]
+    /// Maximum height of a ledge the controller walks up (and down) without jumping
     pub step_offset: f32,
+    /// Stops the controller from walking off ledges while crouched
+    pub ledge_guard: bool,
+    /// Seconds after walking off a ledge during which we can still jump
+    pub coyote_time: f32,
+    /// Seconds a jump press is remembered for, so that pressing it just before landing still jumps
+    pub jump_buffer_time: f32,
+    /// Fastest we can swim through water
+    pub swim_speed: f32,
+    /// How quickly water slows us down, as a fraction of our velocity per second
+    pub swim_drag: f32,
+    /// Downward acceleration while in water
+    pub swim_gravity: f32,
+    /// Upward acceleration when fully under water, it scales with how much of us is submerged
+    pub swim_buoyancy: f32,
+    /// Speed we climb ladders at
+    pub climb_speed: f32,
+    /// Speed we are pushed away from a ladder at when jumping off it
+    pub ladder_jump_speed: f32,
+    /// Minimum speed to start a slide at, by crouching while sprinting
+    pub slide_speed: f32,
+    /// Replaces `friction` while sliding
+    pub slide_friction: f32,
+    /// Longest a slide lasts, it also ends once we slow down to `crouched_speed`
+    pub slide_duration: f32,
+    /// Seconds after a slide ends before we can start another
+    pub slide_cooldown: f32,
+    /// Highest a ledge can be above our feet for us to climb onto it while airborne
+    pub mantle_height: f32,
+    /// Seconds it takes to climb onto a ledge
+    pub mantle_duration: f32,
 }
 
 impl Default for FpsController {
@@ -127,9 +313,250 @@ impl Default for FpsController {
             ground_tick: 0,
             stop_speed: 1.0,
             jump_speed: 8.5,
-            step_offset: 0.0,
+            step_offset: 0.5,
+            ledge_guard: true,
+            coyote_time: 0.1,
+            jump_buffer_time: 0.1,
+            swim_speed: 5.0,
+            swim_drag: 2.0,
+            swim_gravity: 4.0,
+            swim_buoyancy: 6.0,
+            climb_speed: 4.0,
+            ladder_jump_speed: 5.0,
+            slide_speed: 10.0,
+            slide_friction: 0.25,
+            slide_duration: 1.0,
+            slide_cooldown: 0.5,
+            mantle_height: 2.0,
+            mantle_duration: 0.4,
             enable_input: true,
-            sensitivity: 0.001,
+        }
+    }
+}
+
+/// What an [`FpsController`] is in the middle of from one tick to the next, kept up to date by its
+/// systems rather than set up along with it
+#[derive(Component, Clone, Copy, Debug, Default, PartialEq)]
+pub struct FpsControllerState {
+    /// Seconds left to jump in after walking off a ledge
+    pub coyote_timer: f32,
+    /// Seconds left for a jump press to jump once we land
+    pub jump_buffer: f32,
+    /// Whether jump was pressed on the last tick
+    pub jump_held: bool,
+    pub crouched: bool,
+    /// Seconds left before we can grab a ladder again after jumping off one
+    pub ladder_cooldown: f32,
+    /// Seconds left of the current slide
+    pub slide_timer: f32,
+    /// Seconds left before we can start another slide
+    pub slide_cooldown_timer: f32,
+    /// Seconds left that we can keep running along walls for
+    pub wall_run_timer: f32,
+    /// Seconds left of the current mantle
+    pub mantle_timer: f32,
+    pub mantle_start: Vec3,
+    pub mantle_target: Vec3,
+}
+
+/// How mouse movement turns the camera. Sticks have their own [`GamepadLook`], neither one scales
+/// the other.
+#[derive(Component, Clone, Copy, Debug)]
+#[require(SmoothedLook)]
+pub struct LookSettings {
+    /// Radians turned per count of mouse movement, horizontally and vertically. See
+    /// [`LookSettings::from_cm_per_360`] to match the sensitivity of another game.
+    pub sensitivity: Vec2,
+    /// Moving the mouse up looks down
+    pub invert_y: bool,
+    pub acceleration: MouseAcceleration,
+    /// Seconds it takes the turn speed to mostly catch up with the mouse, 0 to turn immediately
+    pub smoothing: f32,
+}
+
+impl Default for LookSettings {
+    fn default() -> Self {
+        Self {
+            sensitivity: Vec2::splat(0.001),
+            invert_y: false,
+            acceleration: MouseAcceleration::None,
+            smoothing: 0.0,
+        }
+    }
+}
+
+impl LookSettings {
+    /// Turns a full circle for every `cm` centimeters the mouse moves, with a mouse that reports
+    /// `dpi` counts per inch. Without acceleration this is the same in every game, so it can be
+    /// measured in one and carried over to another.
+    pub fn from_cm_per_360(cm: f32, dpi: f32) -> Self {
+        Self {
+            sensitivity: Vec2::splat(TAU / (cm / CM_PER_INCH * dpi)),
+            ..default()
+        }
+    }
+
+    /// Centimeters the mouse moves horizontally for a full turn, the inverse of
+    /// [`LookSettings::from_cm_per_360`]
+    pub fn cm_per_360(&self, dpi: f32) -> f32 {
+        TAU / self.sensitivity.x / dpi * CM_PER_INCH
+    }
+}
+
+const CM_PER_INCH: f32 = 2.54;
+
+/// Turn speed of the mouse after the [`LookSettings::smoothing`], in radians per second
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub struct SmoothedLook(pub Vec2);
+
+/// Raises the mouse sensitivity the faster the mouse moves. Speeds are in counts per millisecond,
+/// like Quake's.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum MouseAcceleration {
+    #[default]
+    None,
+    /// Multiplies the sensitivity by `1 + rate * speed`, up to `cap`
+    Linear { rate: f32, cap: f32 },
+    /// Multiplies the sensitivity by `1 + (rate * speed) ^ exponent`, up to `cap`
+    Power { rate: f32, exponent: f32, cap: f32 },
+    /// Quake's `m_accel`, which adds `accel * speed` to a `sensitivity` in Quake's units. Copy both
+    /// from a Quake config to get the same curve, the base sensitivity still comes from
+    /// [`LookSettings::sensitivity`]. A `sensitivity` that isn't positive turns it off.
+    Quake { accel: f32, sensitivity: f32 },
+}
+
+/// How a stick bound to [`FpsActions::Look`] turns the camera, the counterpart of the mouse
+/// [`LookSettings`].
+#[derive(Component, Clone, Copy, Debug)]
+pub struct GamepadLook {
+    /// Turn speed at full deflection, in radians per second
+    pub sensitivity: f32,
+    /// Deflection below which the stick counts as centered, the rest of its range is rescaled
+    /// to start from zero
+    pub deadzone: f32,
+    /// Exponent of the response curve, above 1 makes small deflections turn slower for finer aim
+    pub response_exponent: f32,
+    /// Turns faster the longer the stick is held near its edge, `None` to turn at a constant speed
+    pub acceleration: Option<AimAcceleration>,
+    pub acceleration_timer: f32,
+}
+
+impl Default for GamepadLook {
+    fn default() -> Self {
+        Self {
+            sensitivity: 4.0,
+            deadzone: 0.15,
+            response_exponent: 2.0,
+            acceleration: None,
+            acceleration_timer: 0.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct AimAcceleration {
+    /// Deflection, after the response curve, past which the turn speed starts to build up
+    pub threshold: f32,
+    /// Multiplier of the turn speed once fully built up
+    pub max_multiplier: f32,
+    /// Seconds it takes to build up to `max_multiplier`
+    pub ramp_time: f32,
+}
+
+impl Default for AimAcceleration {
+    fn default() -> Self {
+        Self {
+            threshold: 0.9,
+            max_multiplier: 2.0,
+            ramp_time: 0.5,
+        }
+    }
+}
+
+/// Lets a controller run along walls while airborne. Add it next to the [`FpsController`] to
+/// enable wall-running.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct WallRunConfig {
+    /// Seconds we can spend on walls before touching the ground again
+    pub duration: f32,
+    /// Fraction of the controller's gravity that applies while on a wall
+    pub gravity_scale: f32,
+    /// Minimum speed along a wall to start running on it
+    pub min_speed: f32,
+    /// How far past the controller's radius a wall is looked for, to either side of it
+    pub reach: f32,
+    /// Surfaces whose normal points up or down less than this are walls
+    pub wall_normal_cutoff: f32,
+    /// Speed a jump pushes us away from the wall at
+    pub jump_speed: f32,
+}
+
+impl Default for WallRunConfig {
+    fn default() -> Self {
+        Self {
+            duration: 1.5,
+            gravity_scale: 0.25,
+            min_speed: 5.0,
+            reach: 0.3,
+            wall_normal_cutoff: 0.3,
+            jump_speed: 6.0,
+        }
+    }
+}
+
+/// Hit points of a player, it dies once they run out.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct Health {
+    pub current: f32,
+    pub max: f32,
+}
+
+impl Health {
+    pub fn new(max: f32) -> Self {
+        Self { current: max, max }
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.current <= 0.0
+    }
+
+    /// Takes `amount` off the current health, returning whether that killed us
+    pub fn damage(&mut self, amount: f32) -> bool {
+        let was_dead = self.is_dead();
+        self.current = f32::max(self.current - amount, 0.0);
+        !was_dead && self.is_dead()
+    }
+}
+
+/// Hurts a controller with [`Health`] when it lands hard. Damage scales from nothing at
+/// `safe_speed` to all of its health at `lethal_speed`.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct FallDamage {
+    /// Fastest we can hit the ground at without taking damage
+    pub safe_speed: f32,
+    /// Hitting the ground at this speed or faster kills us
+    pub lethal_speed: f32,
+}
+
+impl Default for FallDamage {
+    fn default() -> Self {
+        Self {
+            safe_speed: 12.0,
+            lethal_speed: 30.0,
+        }
+    }
+}
+
+impl FallDamage {
+    /// Share of its health that hitting the ground at `impact_speed` takes, from 0 to 1. Hitting it
+    /// at `lethal_speed` always kills, even if that isn't any faster than `safe_speed`.
+    pub fn severity(&self, impact_speed: f32) -> f32 {
+        if impact_speed >= self.lethal_speed {
+            1.0
+        } else if impact_speed <= self.safe_speed {
+            0.0
+        } else {
+            (impact_speed - self.safe_speed) / (self.lethal_speed - self.safe_speed)
         }
     }
 }
@@ -0,0 +1,562 @@
+use super::components::*;
+use super::events::*;
+use super::util::{invalid_data, write_file};
+use avian3d::prelude::*;
+use bevy::prelude::*;
+use std::{fs, io, path::Path};
+
+/// Starts every demo file, followed by the version of its format
+const MAGIC: &[u8; 4] = b"FPSD";
+const VERSION: u16 = 2;
+
+type RecordQuery<'w, 's> = Query<
+    'w,
+    's,
+    (
+        &'static mut DemoRecorder,
+        &'static FpsControllerInput,
+        &'static FpsController,
+        &'static FpsControllerState,
+        &'static Transform,
+        &'static LinearVelocity,
+        Has<Grounded>,
+    ),
+>;
+
+type CueQuery<'w, 's> = Query<
+    'w,
+    's,
+    (
+        Entity,
+        &'static mut DemoPlayback,
+        &'static mut FpsController,
+        &'static mut FpsControllerState,
+        &'static mut Collider,
+        &'static mut Transform,
+        &'static mut LinearVelocity,
+    ),
+>;
+
+type PlaybackQuery<'w, 's> = Query<
+    'w,
+    's,
+    (
+        Entity,
+        &'static mut DemoPlayback,
+        &'static mut FpsControllerInput,
+        &'static mut Transform,
+        &'static mut LinearVelocity,
+    ),
+>;
+
+/// The [`FpsControllerInput`] of a player on every tick of the fixed timestep, along with where it
+/// started. Movement is simulated at the fixed timestep, so playing a demo back with
+/// [`DemoPlayback`] in the same level reproduces the same movement.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Demo {
+    /// Seconds per tick of the fixed timestep it was recorded at
+    pub timestep: f32,
+    pub start: Transform,
+    pub start_velocity: Vec3,
+    pub start_state: ControllerState,
+    pub frames: Vec<FpsControllerInput>,
+}
+
+impl Demo {
+    /// Little endian, 21 bytes per tick after a short header
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(121 + self.frames.len() * 21);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        let start = [
+            self.timestep,
+            self.start.translation.x,
+            self.start.translation.y,
+            self.start.translation.z,
+            self.start.rotation.x,
+            self.start.rotation.y,
+            self.start.rotation.z,
+            self.start.rotation.w,
+            self.start_velocity.x,
+            self.start_velocity.y,
+            self.start_velocity.z,
+        ];
+        for value in start {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        self.start_state.write(&mut bytes);
+        bytes.extend_from_slice(&(self.frames.len() as u32).to_le_bytes());
+        for frame in &self.frames {
+            let values = [
+                frame.movement.x,
+                frame.movement.y,
+                frame.movement.z,
+                frame.pitch,
+                frame.yaw,
+            ];
+            for value in values {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+            let buttons = [frame.jump, frame.crouch, frame.sprint, frame.fly];
+            let flags = buttons
+                .into_iter()
+                .enumerate()
+                .fold(0u8, |flags, (bit, pressed)| flags | (pressed as u8) << bit);
+            bytes.push(flags);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let mut reader = Reader(bytes);
+        if &reader.take::<4>()? != MAGIC {
+            return Err(invalid_data("not a demo"));
+        }
+        let version = u16::from_le_bytes(reader.take()?);
+        if version != VERSION {
+            return Err(invalid_data(format!("unsupported demo version {version}")));
+        }
+        let timestep = reader.f32()?;
+        let translation = reader.vec3()?;
+        let rotation = Quat::from_xyzw(reader.f32()?, reader.f32()?, reader.f32()?, reader.f32()?);
+        let start_velocity = reader.vec3()?;
+        let start_state = ControllerState::read(&mut reader)?;
+        let frame_count = u32::from_le_bytes(reader.take()?);
+        let frames = (0..frame_count)
+            .map(|_| {
+                let movement = reader.vec3()?;
+                let pitch = reader.f32()?;
+                let yaw = reader.f32()?;
+                let [flags] = reader.take()?;
+                Ok(FpsControllerInput {
+                    fly: flags & 1 << 3 != 0,
+                    sprint: flags & 1 << 2 != 0,
+                    jump: flags & 1 != 0,
+                    crouch: flags & 1 << 1 != 0,
+                    pitch,
+                    yaw,
+                    movement,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Self {
+            timestep,
+            start: Transform::from_translation(translation).with_rotation(rotation),
+            start_velocity,
+            start_state,
+            frames,
+        })
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    /// Saves the demo to `path` in the format of [`Demo::to_bytes`]
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        write_file(path.as_ref(), self.to_bytes())
+    }
+}
+
+/// What an [`FpsController`] was in the middle of when a [`Demo`] started, so that playing it back
+/// starting mid-jump or crouched moves the same way
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ControllerState {
+    pub move_mode: MoveMode,
+    pub grounded: bool,
+    pub ground_tick: u8,
+    pub height: f32,
+    pub pitch: f32,
+    pub yaw: f32,
+    pub state: FpsControllerState,
+}
+
+impl Default for ControllerState {
+    fn default() -> Self {
+        Self::new(&FpsController::default(), &default(), false)
+    }
+}
+
+impl ControllerState {
+    pub fn new(controller: &FpsController, state: &FpsControllerState, grounded: bool) -> Self {
+        Self {
+            move_mode: controller.move_mode,
+            grounded,
+            ground_tick: controller.ground_tick,
+            height: controller.height,
+            pitch: controller.pitch,
+            yaw: controller.yaw,
+            state: *state,
+        }
+    }
+
+    /// Puts the controller back into this state, leaving its settings alone
+    pub fn apply(&self, controller: &mut FpsController, state: &mut FpsControllerState) {
+        controller.move_mode = self.move_mode;
+        controller.ground_tick = self.ground_tick;
+        controller.height = self.height;
+        controller.pitch = self.pitch;
+        controller.yaw = self.yaw;
+        *state = self.state;
+    }
+
+    /// 67 bytes
+    fn write(&self, bytes: &mut Vec<u8>) {
+        let move_mode = match self.move_mode {
+            MoveMode::Noclip => 0u8,
+            MoveMode::Ground => 1,
+            MoveMode::Swim => 2,
+            MoveMode::Climb => 3,
+            MoveMode::WallRun => 4,
+            MoveMode::Mantle => 5,
+        };
+        let state = &self.state;
+        let flags =
+            self.grounded as u8 | (state.crouched as u8) << 1 | (state.jump_held as u8) << 2;
+        bytes.extend_from_slice(&[move_mode, self.ground_tick, flags]);
+        let values = [
+            self.height,
+            self.pitch,
+            self.yaw,
+            state.coyote_timer,
+            state.jump_buffer,
+            state.slide_timer,
+            state.slide_cooldown_timer,
+            state.wall_run_timer,
+            state.ladder_cooldown,
+            state.mantle_timer,
+            state.mantle_start.x,
+            state.mantle_start.y,
+            state.mantle_start.z,
+            state.mantle_target.x,
+            state.mantle_target.y,
+            state.mantle_target.z,
+        ];
+        for value in values {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    fn read(reader: &mut Reader) -> io::Result<Self> {
+        let [move_mode, ground_tick, flags] = reader.take()?;
+        let move_mode = match move_mode {
+            0 => MoveMode::Noclip,
+            1 => MoveMode::Ground,
+            2 => MoveMode::Swim,
+            3 => MoveMode::Climb,
+            4 => MoveMode::WallRun,
+            5 => MoveMode::Mantle,
+            _ => return Err(invalid_data(format!("unknown move mode {move_mode}"))),
+        };
+        Ok(Self {
+            move_mode,
+            grounded: flags & 1 != 0,
+            ground_tick,
+            height: reader.f32()?,
+            pitch: reader.f32()?,
+            yaw: reader.f32()?,
+            state: FpsControllerState {
+                crouched: flags & 1 << 1 != 0,
+                jump_held: flags & 1 << 2 != 0,
+                coyote_timer: reader.f32()?,
+                jump_buffer: reader.f32()?,
+                slide_timer: reader.f32()?,
+                slide_cooldown_timer: reader.f32()?,
+                wall_run_timer: reader.f32()?,
+                ladder_cooldown: reader.f32()?,
+                mantle_timer: reader.f32()?,
+                mantle_start: reader.vec3()?,
+                mantle_target: reader.vec3()?,
+            },
+        })
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let (bytes, rest) = self
+            .0
+            .split_first_chunk()
+            .ok_or_else(|| invalid_data("demo is cut short"))?;
+        self.0 = rest;
+        Ok(*bytes)
+    }
+
+    fn f32(&mut self) -> io::Result<f32> {
+        self.take().map(f32::from_le_bytes)
+    }
+
+    fn vec3(&mut self) -> io::Result<Vec3> {
+        Ok(Vec3::new(self.f32()?, self.f32()?, self.f32()?))
+    }
+}
+
+/// Records a player's input into a [`Demo`], starting from where it is and what it is doing when
+/// this is added. Remove it, or take the demo out of it, to stop recording.
+#[derive(Component, Clone, Debug, Default)]
+pub struct DemoRecorder(pub Demo);
+
+/// Plays a [`Demo`] back on a player in place of its own input, moving it to the start of the
+/// demo and putting it back in the [`ControllerState`] it started in first. Removed once the last
+/// tick of the demo is played, sending [`DemoFinished`].
+#[derive(Component, Clone, Debug)]
+pub struct DemoPlayback {
+    pub demo: Demo,
+    pub tick: usize,
+    /// Whether the player has been moved to the start of the demo yet
+    cued: bool,
+}
+
+impl DemoPlayback {
+    pub fn new(demo: Demo) -> Self {
+        Self {
+            demo,
+            tick: 0,
+            cued: false,
+        }
+    }
+}
+
+/// Adds the input of this tick to each [`DemoRecorder`], after it is read and before it moves
+/// the player
+pub fn fps_controller_record(time: Res<Time>, mut players: RecordQuery) {
+    for (mut recorder, input, controller, state, transform, velocity, grounded) in &mut players {
+        let demo = &mut recorder.0;
+        if demo.frames.is_empty() {
+            demo.timestep = time.delta_secs();
+            demo.start = *transform;
+            demo.start_velocity = velocity.0;
+            demo.start_state = ControllerState::new(controller, state, grounded);
+        }
+        demo.frames.push(*input);
+    }
+}
+
+/// Moves players given a [`DemoPlayback`] to the start of it at the end of a tick and holds them
+/// there, so that physics finds the ground under the start before the first tick of the demo plays
+/// rather than leaving the ground hits from where they were
+pub fn fps_controller_cue_playback(mut commands: Commands, time: Res<Time>, mut players: CueQuery) {
+    for (
+        entity,
+        mut playback,
+        mut controller,
+        mut state,
+        mut collider,
+        mut transform,
+        mut velocity,
+    ) in &mut players
+    {
+        if playback.cued {
+            continue;
+        }
+        playback.cued = true;
+        if (playback.demo.timestep - time.delta_secs()).abs() > 1e-6 {
+            warn!(
+                "Playing back a demo recorded at a timestep of {}s at {}s, it won't move the same",
+                playback.demo.timestep,
+                time.delta_secs()
+            );
+        }
+        *transform = playback.demo.start;
+        velocity.0 = Vec3::ZERO;
+        let start = playback.demo.start_state;
+        start.apply(&mut controller, &mut state);
+        collider.set_shape(
+            Collider::capsule(controller.radius, controller.height)
+                .shape()
+                .clone(),
+        );
+        set_grounded(&mut commands, entity, start.grounded);
+    }
+}
+
+fn set_grounded(commands: &mut Commands, entity: Entity, grounded: bool) {
+    if grounded {
+        commands.entity(entity).insert(Grounded);
+    } else {
+        commands.entity(entity).remove::<Grounded>();
+    }
+}
+
+/// Feeds each [`DemoPlayback`] the input it recorded for this tick, once it is cued
+pub fn fps_controller_playback(mut commands: Commands, mut players: PlaybackQuery) {
+    for (entity, mut playback, mut input, mut transform, mut velocity) in &mut players {
+        if !playback.cued {
+            continue;
+        }
+        if playback.tick == 0 {
+            // Undoing any push out of what we stand on while held still
+            *transform = playback.demo.start;
+            velocity.0 = playback.demo.start_velocity;
+            // Held still while cued, we may have landed or left the ground by a different reach
+            set_grounded(&mut commands, entity, playback.demo.start_state.grounded);
+        }
+        if let Some(&frame) = playback.demo.frames.get(playback.tick) {
+            *input = frame;
+            playback.tick += 1;
+        }
+        if playback.tick == playback.demo.frames.len() {
+            commands.entity(entity).remove::<DemoPlayback>();
+            commands.send_event(DemoFinished { entity });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{run, spawn_box, spawn_floor, spawn_player, test_app, FLOOR_TOP};
+    use bevy::time::TimeUpdateStrategy;
+
+    fn demo_app() -> App {
+        let mut app = test_app();
+        // One tick per update, so that the input the test writes lands on a known tick
+        let timestep = app.world().resource::<Time<Fixed>>().timestep();
+        app.insert_resource(TimeUpdateStrategy::ManualDuration(timestep));
+        spawn_floor(&mut app, 20.0);
+        // Something to bump into and jump onto along the way
+        spawn_box(
+            &mut app,
+            Vec3::new(-2.0, FLOOR_TOP, -8.0),
+            Vec3::new(2.0, FLOOR_TOP + 0.8, -6.0),
+        );
+        app
+    }
+
+    #[test]
+    fn replays_recorded_input_to_the_same_position() {
+        let mut app = demo_app();
+        let start = Vec3::new(0.5, FLOOR_TOP + 1.6, 0.0);
+        let player = spawn_player(&mut app, start);
+        run(&mut app, 0.5);
+        app.world_mut()
+            .entity_mut(player)
+            .insert(DemoRecorder::default());
+        for tick in 0..240 {
+            let mut input = app
+                .world_mut()
+                .get_mut::<FpsControllerInput>(player)
+                .unwrap();
+            input.movement = Vec3::new(if tick > 120 { 1.0 } else { 0.3 }, 0.0, 1.0);
+            input.sprint = tick < 100;
+            input.jump = (50..60).contains(&tick);
+            input.crouch = (160..200).contains(&tick);
+            input.yaw += 0.01;
+            input.pitch = -0.2;
+            app.update();
+        }
+        let recorded = app
+            .world_mut()
+            .entity_mut(player)
+            .take::<DemoRecorder>()
+            .unwrap()
+            .0;
+        let end = app.world().get::<Position>(player).unwrap().0;
+        assert_eq!(recorded.frames.len(), 240);
+        assert!(end.distance(start) > 10.0, "only moved to {end}");
+
+        let path = std::env::temp_dir()
+            .join(format!("demo-test-{}", std::process::id()))
+            .join("replay.demo");
+        recorded.save(&path).unwrap();
+        let demo = Demo::load(&path).unwrap();
+        assert_eq!(demo, recorded);
+
+        // Play it back in a fresh world, from a player still falling somewhere else
+        let mut app = demo_app();
+        let player = spawn_player(&mut app, Vec3::new(10.0, FLOOR_TOP + 6.0, 10.0));
+        run(&mut app, 0.1);
+        assert!(app.world().get::<Grounded>(player).is_none());
+        app.world_mut()
+            .entity_mut(player)
+            .insert(DemoPlayback::new(demo));
+        // One tick to cue it
+        for _ in 0..241 {
+            app.update();
+        }
+        let world = app.world();
+        assert!(world.get::<DemoPlayback>(player).is_none());
+        assert_eq!(world.resource::<Events<DemoFinished>>().len(), 1);
+        // Physics finds the ground under the start again from a hair off where it did recording
+        let position = world.get::<Position>(player).unwrap().0;
+        assert!(position.distance(end) < 0.01, "at {position}, not {end}");
+    }
+
+    #[test]
+    fn replays_demo_started_mid_jump_while_crouched() {
+        let input = |tick: usize| FpsControllerInput {
+            movement: Vec3::new(0.2, 0.0, 1.0),
+            sprint: true,
+            crouch: (10..50).contains(&tick),
+            jump: (20..40).contains(&tick),
+            ..default()
+        };
+        let mut app = demo_app();
+        let start = Vec3::new(0.5, FLOOR_TOP + 1.6, 8.0);
+        let player = spawn_player(&mut app, start);
+        run(&mut app, 0.5);
+        for tick in 0..120 {
+            if tick == 25 {
+                app.world_mut()
+                    .entity_mut(player)
+                    .insert(DemoRecorder::default());
+            }
+            *app.world_mut()
+                .get_mut::<FpsControllerInput>(player)
+                .unwrap() = input(tick);
+            app.update();
+        }
+        let recorded = app
+            .world_mut()
+            .entity_mut(player)
+            .take::<DemoRecorder>()
+            .unwrap()
+            .0;
+        let end = app.world().get::<Position>(player).unwrap().0;
+        let state = recorded.start_state;
+        assert!(
+            !state.grounded && state.state.crouched && state.state.jump_held,
+            "{state:?}"
+        );
+        assert!(state.height < FpsController::default().upright_height);
+        let demo = Demo::from_bytes(&recorded.to_bytes()).unwrap();
+        assert_eq!(demo, recorded);
+
+        // The fresh player is standing still on the ground elsewhere when the demo takes over
+        let mut app = demo_app();
+        let player = spawn_player(&mut app, Vec3::new(-10.0, FLOOR_TOP + 1.6, 10.0));
+        run(&mut app, 0.5);
+        assert!(app.world().get::<Grounded>(player).is_some());
+        app.world_mut()
+            .entity_mut(player)
+            .insert(DemoPlayback::new(demo));
+        for _ in 0..96 {
+            app.update();
+        }
+        let world = app.world();
+        assert!(world.get::<DemoPlayback>(player).is_none());
+        assert_eq!(world.get::<Position>(player).unwrap().0, end);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let demo = Demo {
+            timestep: 1.0 / 64.0,
+            frames: vec![FpsControllerInput::default(); 3],
+            ..default()
+        };
+        let bytes = demo.to_bytes();
+        assert_eq!(bytes.len(), 121 + 3 * 21);
+        assert_eq!(Demo::from_bytes(&bytes).unwrap(), demo);
+        for bytes in [&bytes[..bytes.len() - 1], b"RIFF....", b""] {
+            let error = Demo::from_bytes(bytes).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+        let mut newer = bytes.clone();
+        newer[4] = 3;
+        let error = Demo::from_bytes(&newer).unwrap_err();
+        assert_eq!(error.to_string(), "unsupported demo version 3");
+    }
+}
@@ -0,0 +1,92 @@
+use super::bindings::Binding;
+use super::components::{FpsActions, MoveMode};
+use bevy::prelude::*;
+
+/// Sent when a controller jumps, including coyote time and buffered jumps.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct Jumped {
+    pub entity: Entity,
+}
+
+/// Sent when a controller becomes [`Grounded`](crate::Grounded).
+#[derive(Event, Clone, Copy, Debug)]
+pub struct Landed {
+    pub entity: Entity,
+    /// Downward speed the controller hit the ground with
+    pub impact_speed: f32,
+}
+
+/// Sent when a controller stops being [`Grounded`](crate::Grounded), by jumping or falling.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct LeftGround {
+    pub entity: Entity,
+}
+
+/// Sent when a controller starts crouching.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct StartedCrouch {
+    pub entity: Entity,
+}
+
+/// Sent when a controller starts standing back up, which can be delayed by a low ceiling.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct StoppedCrouch {
+    pub entity: Entity,
+}
+
+/// Sent when a controller switches movement mode, e.g. when it starts swimming or toggles noclip.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct ModeChanged {
+    pub entity: Entity,
+    pub mode: MoveMode,
+}
+
+/// Sent when a player dies, by running out of [`Health`](crate::Health), falling below the
+/// [`KillPlane`](crate::KillPlane) or touching a [`KillVolume`](crate::KillVolume).
+#[derive(Event, Clone, Copy, Debug)]
+pub struct Died {
+    pub entity: Entity,
+}
+
+/// Sent when a player that died is moved back to its [`RespawnPoint`](crate::RespawnPoint).
+#[derive(Event, Clone, Copy, Debug)]
+pub struct PlayerRespawned {
+    pub entity: Entity,
+}
+
+/// Unloads the current level and loads the one at `index` in the
+/// [`LevelRegistry`](crate::LevelRegistry), moving players to its spawn point.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct LoadLevel {
+    pub index: usize,
+}
+
+/// Starts listening for the next key or mouse button press to bind `action` to, see
+/// [`Bindings`](crate::Bindings).
+#[derive(Event, Clone, Copy, Debug)]
+pub struct StartRebind {
+    pub action: FpsActions,
+}
+
+/// Sent when an action is bound to a new key or mouse button. The `conflicts` were bound to it
+/// before, and are not anymore.
+#[derive(Event, Clone, Debug)]
+pub struct Rebound {
+    pub action: FpsActions,
+    pub binding: Binding,
+    pub conflicts: Vec<FpsActions>,
+}
+
+/// Sent when a player reaches a [`LevelExit`](crate::LevelExit), before the next level loads.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct LevelCompleted {
+    pub entity: Entity,
+    /// Index of the level in the [`LevelRegistry`](crate::LevelRegistry)
+    pub level: usize,
+}
+
+/// Sent when a [`DemoPlayback`](crate::DemoPlayback) plays its last tick.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct DemoFinished {
+    pub entity: Entity,
+}
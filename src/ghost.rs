@@ -0,0 +1,504 @@
+use super::components::*;
+use super::events::*;
+use super::util::{invalid_data, write_file};
+use avian3d::prelude::*;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    f32::consts::TAU,
+    fs, io,
+    path::{Component, Path, PathBuf},
+};
+
+/// Version of the ghost files written by [`Ghost::save`], files of other versions aren't loaded
+pub const GHOST_VERSION: u32 = 1;
+
+/// Where a [`Ghost`] was at a moment of its run
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct GhostPose {
+    /// Seconds since the start of the run
+    pub time: f32,
+    pub translation: Vec3,
+    pub pitch: f32,
+    pub yaw: f32,
+}
+
+impl GhostPose {
+    /// Where the ghost's body is and which way it faces, leaving out the pitch of its head
+    pub fn transform(&self) -> Transform {
+        Transform::from_translation(self.translation).with_rotation(Quat::from_rotation_y(self.yaw))
+    }
+}
+
+/// A player's run through a level, as their pose over time. Saved as RON.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Ghost {
+    pub version: u32,
+    pub poses: Vec<GhostPose>,
+}
+
+impl Default for Ghost {
+    fn default() -> Self {
+        Self {
+            version: GHOST_VERSION,
+            poses: Vec::new(),
+        }
+    }
+}
+
+/// Just the version of a ghost file, read first so that files of other versions are rejected
+/// rather than misread
+#[derive(Deserialize)]
+struct GhostVersion {
+    version: u32,
+}
+
+impl Ghost {
+    /// Seconds from the start to the end of the run
+    pub fn duration(&self) -> f32 {
+        self.poses.last().map_or(0.0, |pose| pose.time)
+    }
+
+    /// The pose at `time` into the run, in between recorded poses, or at the end once the run is
+    /// over
+    pub fn pose_at(&self, time: f32) -> Option<GhostPose> {
+        let next = self.poses.partition_point(|pose| pose.time <= time);
+        let Some(&after) = self.poses.get(next) else {
+            return self.poses.last().copied();
+        };
+        let Some(&before) = next.checked_sub(1).and_then(|index| self.poses.get(index)) else {
+            return Some(after);
+        };
+        let t = (time - before.time) / (after.time - before.time);
+        // The yaw wraps around, turn the short way
+        let yaw_change = (after.yaw - before.yaw + TAU / 2.0).rem_euclid(TAU) - TAU / 2.0;
+        Some(GhostPose {
+            time,
+            translation: before.translation.lerp(after.translation, t),
+            pitch: before.pitch.lerp(after.pitch, t),
+            yaw: before.yaw + yaw_change * t,
+        })
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let ron = fs::read_to_string(path)?;
+        let GhostVersion { version } = ron::from_str(&ron).map_err(invalid_data)?;
+        if version != GHOST_VERSION {
+            return Err(invalid_data(format!("unsupported ghost version {version}")));
+        }
+        ron::from_str(&ron).map_err(invalid_data)
+    }
+
+    /// Saves the ghost to `path`, e.g. the one [`GhostDirectory::ghost_path`] gives for its level
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let ron = ron::to_string(self).map_err(io::Error::other)?;
+        write_file(path.as_ref(), ron)
+    }
+}
+
+/// The directory that the best run of each level is saved in, at the same path as the level's glTF
+/// is in the assets
+#[derive(Resource, Clone, Debug)]
+pub struct GhostDirectory(pub PathBuf);
+
+impl GhostDirectory {
+    pub fn ghost_path(&self, level: &LevelInfo) -> PathBuf {
+        // Only the names along the path, so that it stays inside of the directory
+        let path: PathBuf = Path::new(&level.path)
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => Some(name),
+                _ => None,
+            })
+            .collect();
+        self.0.join(path.with_extension("ghost.ron"))
+    }
+}
+
+/// The best run of the current level, if it has been finished before
+#[derive(Resource, Clone, Debug, Default)]
+pub struct BestGhost(pub Option<Ghost>);
+
+/// Records the run of a player through the current level
+#[derive(Component, Clone, Debug, Default)]
+pub struct GhostRecorder {
+    pub ghost: Ghost,
+    pub time: f32,
+}
+
+/// An entity that replays the [`BestGhost`], alongside the players' runs
+#[derive(Component, Clone, Debug, Default)]
+pub struct GhostReplay {
+    pub time: f32,
+}
+
+/// Loads the best run of the level that just started and starts recording the players' runs
+pub(crate) fn start_ghost_runs(
+    mut commands: Commands,
+    main_scene: Res<MainScene>,
+    registry: Res<LevelRegistry>,
+    directory: Res<GhostDirectory>,
+    players: Query<Entity, With<LogicalPlayer>>,
+) {
+    let best = registry.levels.get(main_scene.level).and_then(|level| {
+        let path = directory.ghost_path(level);
+        match Ghost::load(&path) {
+            Ok(ghost) => Some(ghost),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => None,
+            Err(error) => {
+                warn!("Can't load the ghost {}: {error}", path.display());
+                None
+            }
+        }
+    });
+    if let Some(start) = best.as_ref().and_then(|ghost| ghost.pose_at(0.0)) {
+        commands.spawn((
+            Name::new("Ghost"),
+            GhostReplay::default(),
+            start.transform(),
+            // Poses are a tick apart like the players' movement, smooth them out the same way
+            TransformInterpolation,
+            Visibility::default(),
+        ));
+    }
+    commands.insert_resource(BestGhost(best));
+    for player in &players {
+        commands.entity(player).insert(GhostRecorder::default());
+    }
+}
+
+/// Stops the runs and the ghost of the level that is being left
+pub(crate) fn end_ghost_runs(
+    mut commands: Commands,
+    ghosts: Query<Entity, With<GhostReplay>>,
+    players: Query<Entity, With<GhostRecorder>>,
+) {
+    for ghost in &ghosts {
+        commands.entity(ghost).despawn();
+    }
+    for player in &players {
+        commands.entity(player).remove::<GhostRecorder>();
+    }
+}
+
+pub(crate) fn record_ghost_runs(
+    time: Res<Time>,
+    mut players: Query<(&mut GhostRecorder, &Transform, &FpsController)>,
+) {
+    for (mut recorder, transform, controller) in &mut players {
+        let pose = GhostPose {
+            time: recorder.time,
+            translation: transform.translation,
+            pitch: controller.pitch,
+            yaw: controller.yaw,
+        };
+        recorder.ghost.poses.push(pose);
+        recorder.time += time.delta_secs();
+    }
+}
+
+/// Saves the run of a player that completes the level if it beats the best one
+pub(crate) fn finish_ghost_runs(
+    mut commands: Commands,
+    mut completed: EventReader<LevelCompleted>,
+    mut players: Query<(&mut GhostRecorder, &Transform, &FpsController)>,
+    mut best: ResMut<BestGhost>,
+    registry: Res<LevelRegistry>,
+    directory: Res<GhostDirectory>,
+) {
+    for &LevelCompleted { entity, level } in completed.read() {
+        let Ok((mut recorder, transform, controller)) = players.get_mut(entity) else {
+            continue;
+        };
+        let end = GhostPose {
+            time: recorder.time,
+            translation: transform.translation,
+            pitch: controller.pitch,
+            yaw: controller.yaw,
+        };
+        let mut ghost = std::mem::take(&mut recorder.ghost);
+        ghost.poses.push(end);
+        commands.entity(entity).remove::<GhostRecorder>();
+        if best
+            .0
+            .as_ref()
+            .is_some_and(|best| best.duration() <= ghost.duration())
+        {
+            continue;
+        }
+        if let Some(level) = registry.levels.get(level) {
+            let path = directory.ghost_path(level);
+            if let Err(error) = ghost.save(&path) {
+                warn!("Can't save the ghost to {}: {error}", path.display());
+            }
+        }
+        best.0 = Some(ghost);
+    }
+}
+
+/// Moves ghosts along with the players' runs, tick by tick like they are recorded
+pub(crate) fn move_ghosts(
+    time: Res<Time>,
+    best: Res<BestGhost>,
+    mut ghosts: Query<(&mut GhostReplay, &mut Transform)>,
+) {
+    let Some(ghost) = &best.0 else {
+        return;
+    };
+    for (mut replay, mut transform) in &mut ghosts {
+        if let Some(pose) = ghost.pose_at(replay.time) {
+            *transform = pose.transform();
+        }
+        replay.time += time.delta_secs();
+    }
+}
+
+/// Gives ghosts a translucent body the size of a player
+pub(crate) fn ghost_visuals(
+    mut commands: Commands,
+    ghosts: Query<Entity, Added<GhostReplay>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    for ghost in &ghosts {
+        let controller = FpsController::default();
+        commands.entity(ghost).insert((
+            Mesh3d(meshes.add(Capsule3d::new(controller.radius, controller.height))),
+            MeshMaterial3d(materials.add(StandardMaterial {
+                base_color: Color::srgba(0.6, 0.8, 1.0, 0.35),
+                alpha_mode: AlphaMode::Blend,
+                unlit: true,
+                ..default()
+            })),
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::level::fps_controller_level_exit;
+    use crate::movement::fps_controller_move;
+    use crate::test_util::{run, spawn_box, spawn_player, test_app_with, FLOOR_TOP};
+    use crate::GhostPlugin;
+    use bevy::state::app::StatesPlugin;
+
+    const START: Vec3 = Vec3::new(0.0, FLOOR_TOP + 1.5, 5.0);
+
+    fn pose(time: f32, x: f32, yaw: f32) -> GhostPose {
+        GhostPose {
+            time,
+            translation: Vec3::new(x, 0.0, 0.0),
+            pitch: 0.0,
+            yaw,
+        }
+    }
+
+    #[test]
+    fn interpolates_poses() {
+        let ghost = Ghost {
+            poses: vec![
+                pose(0.0, 0.0, 3.0),
+                pose(1.0, 2.0, -3.0),
+                pose(3.0, 2.0, 0.0),
+            ],
+            ..default()
+        };
+        assert_eq!(ghost.duration(), 3.0);
+        assert_eq!(ghost.pose_at(-1.0), Some(ghost.poses[0]));
+        let halfway = ghost.pose_at(0.5).unwrap();
+        assert_eq!(halfway.translation.x, 1.0);
+        // From just short of half a turn to just past it, rather than all the way around
+        let wrapped = (halfway.yaw.rem_euclid(TAU) - TAU / 2.0).abs();
+        assert!(wrapped < 1e-5, "yaw {}", halfway.yaw);
+        assert_eq!(ghost.pose_at(1.0).unwrap().translation.x, 2.0);
+        assert_eq!(ghost.pose_at(10.0), Some(ghost.poses[2]));
+        assert_eq!(Ghost::default().pose_at(1.0), None);
+    }
+
+    #[test]
+    fn saves_and_loads_versioned_ghosts() {
+        let directory =
+            GhostDirectory(std::env::temp_dir().join(format!("ghost-test-{}", std::process::id())));
+        let level = LevelInfo {
+            name: "Playground".to_string(),
+            path: "levels/playground.glb".to_string(),
+        };
+        let path = directory.ghost_path(&level);
+        assert_eq!(path, directory.0.join("levels/playground.ghost.ron"));
+        // Levels with the same name in different directories keep their own ghosts
+        let path_of = |path: &str| {
+            directory.ghost_path(&LevelInfo {
+                name: "Playground".to_string(),
+                path: path.to_string(),
+            })
+        };
+        assert_ne!(path_of("a/playground.glb"), path_of("b/playground.glb"));
+        assert_eq!(
+            path_of("../../playground.glb"),
+            directory.0.join("playground.ghost.ron")
+        );
+
+        let ghost = Ghost {
+            poses: vec![pose(0.0, 1.0, 0.5), pose(0.5, 2.0, 0.25)],
+            ..default()
+        };
+        ghost.save(&path).unwrap();
+        assert_eq!(Ghost::load(&path).unwrap(), ghost);
+
+        let newer = Ghost {
+            version: GHOST_VERSION + 1,
+            ..ghost
+        };
+        newer.save(&path).unwrap();
+        let error = Ghost::load(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        // Only the version needs to make sense to be turned down
+        fs::write(&path, "(version: 0, poses: 12)").unwrap();
+        assert!(Ghost::load(&path)
+            .unwrap_err()
+            .to_string()
+            .contains("version 0"));
+    }
+
+    /// A level with a floor and an exit ahead of the [`START`], which starts over there once it is
+    /// completed, like it would with the level loaded by the [`crate::LevelPlugin`]
+    fn ghost_app(directory: PathBuf) -> App {
+        let mut app = test_app_with((StatesPlugin, GhostPlugin { directory }));
+        app.init_state::<LevelState>()
+            .add_event::<LoadLevel>()
+            .add_event::<LevelCompleted>()
+            .insert_resource(LevelRegistry {
+                levels: vec![LevelInfo {
+                    name: "Playground".to_string(),
+                    path: "levels/playground.glb".to_string(),
+                }],
+            })
+            .insert_resource(MainScene {
+                handle: default(),
+                is_loaded: true,
+                level: 0,
+            })
+            .add_systems(
+                FixedUpdate,
+                (
+                    fps_controller_level_exit
+                        .after(fps_controller_move)
+                        .run_if(in_state(LevelState::Playing)),
+                    restart_level.after(finish_ghost_runs),
+                ),
+            );
+        spawn_box(
+            &mut app,
+            Vec3::new(-10.0, FLOOR_TOP - 1.0, -20.0),
+            Vec3::new(10.0, FLOOR_TOP, 20.0),
+        );
+        app.world_mut().spawn((
+            Collider::cuboid(4.0, 3.0, 1.0),
+            Sensor,
+            CollisionLayers::new(TRIGGER_LAYER, LayerMask::ALL),
+            LevelExit,
+            Transform::from_xyz(0.0, FLOOR_TOP + 1.5, -5.0),
+        ));
+        app
+    }
+
+    /// Moves the players back to the [`START`] when the level is completed, and starts it over
+    fn restart_level(
+        mut load_level: EventReader<LoadLevel>,
+        mut players: Query<(&mut Transform, &mut LinearVelocity), With<LogicalPlayer>>,
+        state: Res<State<LevelState>>,
+        mut next_state: ResMut<NextState<LevelState>>,
+    ) {
+        if load_level.read().last().is_some() {
+            for (mut transform, mut velocity) in &mut players {
+                transform.translation = START;
+                *velocity = LinearVelocity::ZERO;
+            }
+            next_state.set(LevelState::Loading);
+        } else if *state.get() == LevelState::Loading {
+            next_state.set(LevelState::Playing);
+        }
+    }
+
+    #[test]
+    fn replays_best_run_of_the_level() {
+        let directory =
+            std::env::temp_dir().join(format!("ghost-replay-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        let mut app = ghost_app(directory.clone());
+        let player = spawn_player(&mut app, START);
+        let ghosts = |app: &mut App| {
+            app.world_mut()
+                .query_filtered::<&Transform, With<GhostReplay>>()
+                .iter(app.world())
+                .map(|transform| transform.translation)
+                .collect::<Vec<_>>()
+        };
+        // Walks to the exit, returning the duration of the best run after it
+        let walk_to_exit = |app: &mut App, movement: Vec3| {
+            app.world_mut()
+                .get_mut::<FpsControllerInput>(player)
+                .unwrap()
+                .movement = movement;
+            for _ in 0..300 {
+                app.update();
+                if !app.world().resource::<Events<LevelCompleted>>().is_empty() {
+                    break;
+                }
+            }
+            app.world_mut()
+                .get_mut::<FpsControllerInput>(player)
+                .unwrap()
+                .movement = Vec3::ZERO;
+            run(app, 1.0);
+            app.world()
+                .resource::<BestGhost>()
+                .0
+                .as_ref()
+                .unwrap()
+                .duration()
+        };
+
+        run(&mut app, 1.0);
+        assert!(app.world().get::<GhostRecorder>(player).is_some());
+        assert!(app.world().resource::<BestGhost>().0.is_none());
+        assert!(ghosts(&mut app).is_empty());
+
+        let level = app.world().resource::<LevelRegistry>().levels[0].clone();
+        let path = GhostDirectory(directory).ghost_path(&level);
+        let slow = walk_to_exit(&mut app, Vec3::new(0.0, 0.0, 0.5));
+        assert!(slow > 1.0, "took {slow}s");
+        assert_eq!(Ghost::load(&path).unwrap().duration(), slow);
+
+        // The run went from the spawn point to the exit, and the level started over with its
+        // ghost following it
+        let best = app.world().resource::<BestGhost>().0.clone().unwrap();
+        let first = best.poses[0].translation;
+        assert!(first.xz().distance(START.xz()) < 0.1, "from {first}");
+        let last = best.poses.last().unwrap().translation;
+        assert!(last.z < -3.0, "to {last}");
+        let (replay, transform) = app
+            .world_mut()
+            .query::<(&GhostReplay, &Transform)>()
+            .single(app.world())
+            .unwrap();
+        // In step with the run it is racing, and in between its poses of the last ticks
+        let recorder = app.world().get::<GhostRecorder>(player).unwrap();
+        assert!(replay.time > 0.0);
+        assert_eq!(replay.time, recorder.time);
+        let pose = best.pose_at(replay.time).unwrap().translation;
+        assert!(
+            transform.translation.distance(pose) < 0.2,
+            "at {} instead of {pose}",
+            transform.translation
+        );
+        let fast = walk_to_exit(&mut app, Vec3::Z);
+        assert!(fast < slow, "took {fast}s after {slow}s");
+        assert_eq!(Ghost::load(&path).unwrap().duration(), fast);
+
+        // A slower run doesn't replace the best one
+        assert_eq!(walk_to_exit(&mut app, Vec3::new(0.0, 0.0, 0.5)), fast);
+        assert_eq!(Ghost::load(&path).unwrap().duration(), fast);
+    }
+}
@@ -1,37 +1,37 @@
 use super::components::*;
+use super::demo::DemoPlayback;
 use bevy::prelude::*;
 use leafwing_input_manager::prelude::*;
 use std::f32::consts::*;
 
 const ANGLE_EPSILON: f32 = 0.001953125;
 
+/// Reads each controller's own [`ActionState`] into its [`FpsControllerInput`]. Controllers
+/// without an action state are left alone, so they can be driven by writing their input directly,
+/// as are controllers playing back a demo.
 pub fn fps_controller_input(
-    action_state_query: Query<&ActionState<FpsActions>>,
-    mut query: Query<(&FpsController, &mut FpsControllerInput)>,
+    mut query: Query<
+        (
+            &FpsController,
+            &ActionState<FpsActions>,
+            &mut FpsControllerInput,
+        ),
+        Without<DemoPlayback>,
+    >,
 ) {
-    let Ok((controller, mut input)) = query.single_mut() else {
-        return;
-    };
-
-    if !controller.enable_input {
-        return;
-    }
-
-    for action_state in action_state_query.iter() {
-        let mouse_movement = action_state.axis_pair(&FpsActions::MousePosition);
-        let mouse_delta = mouse_movement.xy() * controller.sensitivity;
-
-        input.pitch = (input.pitch - mouse_delta.y)
-            .clamp(-FRAC_PI_2 + ANGLE_EPSILON, FRAC_PI_2 - ANGLE_EPSILON);
-        input.yaw -= mouse_delta.x;
-        if input.yaw.abs() > PI {
-            input.yaw = input.yaw.rem_euclid(TAU);
+    for (controller, action_state, mut input) in query.iter_mut() {
+        if !controller.enable_input {
+            continue;
         }
 
+        // Sticks move us by how far they are pushed, buttons all the way
+        let stick = action_state.clamped_axis_pair(&FpsActions::Move);
         input.movement = Vec3::new(
-            get_axis(action_state, FpsActions::Right, FpsActions::Left),
+            (get_axis(action_state, FpsActions::Right, FpsActions::Left) + stick.x)
+                .clamp(-1.0, 1.0),
             get_axis(action_state, FpsActions::Jump, FpsActions::Sprint),
-            get_axis(action_state, FpsActions::Forward, FpsActions::Backward),
+            (get_axis(action_state, FpsActions::Forward, FpsActions::Backward) + stick.y)
+                .clamp(-1.0, 1.0),
         );
 
         input.fly = action_state.just_pressed(&FpsActions::Fly);
@@ -41,6 +41,144 @@ pub fn fps_controller_input(
     }
 }
 
+type LookQuery<'w, 's> = Query<
+    'w,
+    's,
+    (
+        &'static FpsController,
+        &'static ActionState<FpsActions>,
+        &'static mut FpsControllerInput,
+        Option<(&'static LookSettings, &'static mut SmoothedLook)>,
+        Option<&'static mut GamepadLook>,
+    ),
+    Without<DemoPlayback>,
+>;
+
+/// Applies mouse movement and the look stick to the look angles. Runs every frame rather than at
+/// the fixed timestep so that looking around stays responsive at any frame rate.
+pub fn fps_controller_look_input(time: Res<Time>, mut query: LookQuery) {
+    for (controller, action_state, mut input, look_settings, gamepad_look) in query.iter_mut() {
+        if !controller.enable_input {
+            continue;
+        }
+
+        let mut look_delta = Vec2::ZERO;
+        if let Some((look_settings, mut smoothed)) = look_settings {
+            let mouse_movement = action_state.axis_pair(&FpsActions::MousePosition);
+            look_delta += mouse_look_delta(
+                look_settings,
+                &mut smoothed,
+                mouse_movement,
+                time.delta_secs(),
+            );
+        }
+        if let Some(mut gamepad_look) = gamepad_look {
+            let stick = action_state.axis_pair(&FpsActions::Look);
+            // Pushing the stick up looks up, unlike moving the mouse up
+            look_delta += stick_look_delta(&mut gamepad_look, stick, time.delta_secs())
+                * Vec2::new(1.0, -1.0);
+        }
+
+        input.pitch = (input.pitch - look_delta.y)
+            .clamp(-FRAC_PI_2 + ANGLE_EPSILON, FRAC_PI_2 - ANGLE_EPSILON);
+        input.yaw -= look_delta.x;
+        if input.yaw.abs() > PI {
+            input.yaw = input.yaw.rem_euclid(TAU);
+        }
+    }
+}
+
+/// Turns off Quake mouse acceleration set up with a sensitivity it can't divide by, as soon as the
+/// [`LookSettings`] are added or changed
+pub fn fps_controller_look_settings(
+    mut query: Query<(Entity, &mut LookSettings), Changed<LookSettings>>,
+) {
+    for (entity, mut look_settings) in &mut query {
+        if let MouseAcceleration::Quake { sensitivity, .. } = look_settings.acceleration {
+            if sensitivity.is_nan() || sensitivity <= 0.0 {
+                warn!(
+                    "Quake mouse acceleration of {entity} needs a positive sensitivity, not {sensitivity}, turning it off"
+                );
+                look_settings.acceleration = MouseAcceleration::None;
+            }
+        }
+    }
+}
+
+/// Turns mouse movement, in counts, into how far to turn this frame, in radians
+fn mouse_look_delta(
+    look: &LookSettings,
+    smoothed: &mut SmoothedLook,
+    counts: Vec2,
+    dt: f32,
+) -> Vec2 {
+    let speed = if dt > 0.0 {
+        counts.length() / (dt * 1000.0)
+    } else {
+        0.0
+    };
+    let mut delta = counts * look.sensitivity * mouse_acceleration(look.acceleration, speed);
+    if look.invert_y {
+        delta.y = -delta.y;
+    }
+    if look.smoothing > 0.0 && dt > 0.0 {
+        // Smoothing the turn speed rather than each frame's turn keeps it the same at any frame
+        // rate, and we still end up turning as far as the mouse moved
+        let catch_up = 1.0 - f32::exp(-dt / look.smoothing);
+        smoothed.0 = smoothed.0.lerp(delta / dt, catch_up);
+        delta = smoothed.0 * dt;
+    }
+    delta
+}
+
+/// Sensitivity multiplier at a mouse speed in counts per millisecond
+fn mouse_acceleration(acceleration: MouseAcceleration, speed: f32) -> f32 {
+    match acceleration {
+        MouseAcceleration::None => 1.0,
+        MouseAcceleration::Linear { rate, cap } => f32::min(1.0 + rate * speed, cap),
+        MouseAcceleration::Power {
+            rate,
+            exponent,
+            cap,
+        } => f32::min(1.0 + (rate * speed).powf(exponent), cap),
+        MouseAcceleration::Quake { accel, sensitivity } => {
+            (sensitivity + accel * speed) / sensitivity
+        }
+    }
+}
+
+/// Turns the look stick's deflection into how far to turn this frame, in radians
+fn stick_look_delta(look: &mut GamepadLook, stick: Vec2, dt: f32) -> Vec2 {
+    let stick = stick_response(stick, look.deadzone, look.response_exponent);
+    let mut multiplier = 1.0;
+    if let Some(acceleration) = look.acceleration {
+        if stick.length() >= acceleration.threshold {
+            look.acceleration_timer =
+                f32::min(look.acceleration_timer + dt, acceleration.ramp_time);
+        } else {
+            look.acceleration_timer = 0.0;
+        }
+        let ramp = if acceleration.ramp_time > 0.0 {
+            look.acceleration_timer / acceleration.ramp_time
+        } else {
+            1.0
+        };
+        multiplier = 1.0 + (acceleration.max_multiplier - 1.0) * ramp;
+    }
+    stick * look.sensitivity * multiplier * dt
+}
+
+/// Drops the deadzone from the stick's range and applies the response curve to what is left,
+/// keeping the direction it is pushed in
+fn stick_response(stick: Vec2, deadzone: f32, exponent: f32) -> Vec2 {
+    let deflection = stick.length();
+    if deflection <= deadzone {
+        return Vec2::ZERO;
+    }
+    let live = f32::min((deflection - deadzone) / (1.0 - deadzone), 1.0);
+    stick / deflection * live.powf(exponent)
+}
+
 fn get_pressed(key_input: &ActionState<FpsActions>, key: FpsActions) -> f32 {
     if key_input.pressed(&key) {
         1.0
@@ -52,3 +190,174 @@ fn get_pressed(key_input: &ActionState<FpsActions>, key: FpsActions) -> f32 {
 fn get_axis(key_input: &ActionState<FpsActions>, key_pos: FpsActions, key_neg: FpsActions) -> f32 {
     get_pressed(key_input, key_pos) - get_pressed(key_input, key_neg)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::ecs::system::RunSystemOnce;
+
+    #[test]
+    fn cm_per_360_turns_a_full_circle() {
+        let look = LookSettings::from_cm_per_360(30.0, 800.0);
+        // 30 cm at 800 counts per inch
+        let counts = 30.0 / 2.54 * 800.0;
+        assert!((counts * look.sensitivity.x - TAU).abs() < 1e-4);
+        assert_eq!(look.sensitivity.x, look.sensitivity.y);
+        assert!((look.cm_per_360(800.0) - 30.0).abs() < 1e-3);
+        // Twice the DPI needs half the distance
+        assert!((look.cm_per_360(1600.0) - 15.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn mouse_look_uses_each_axis_sensitivity() {
+        let mut smoothed = SmoothedLook::default();
+        let mut look = LookSettings {
+            sensitivity: Vec2::new(0.002, 0.001),
+            ..default()
+        };
+        let counts = Vec2::new(10.0, 10.0);
+        let delta = mouse_look_delta(&look, &mut smoothed, counts, 0.01);
+        assert!(delta.abs_diff_eq(Vec2::new(0.02, 0.01), 1e-6), "{delta}");
+        look.invert_y = true;
+        let delta = mouse_look_delta(&look, &mut smoothed, counts, 0.01);
+        assert!(delta.abs_diff_eq(Vec2::new(0.02, -0.01), 1e-6), "{delta}");
+    }
+
+    #[test]
+    fn mouse_acceleration_curves() {
+        let linear = MouseAcceleration::Linear {
+            rate: 0.5,
+            cap: 3.0,
+        };
+        assert_eq!(mouse_acceleration(linear, 0.0), 1.0);
+        assert_eq!(mouse_acceleration(linear, 2.0), 2.0);
+        assert_eq!(mouse_acceleration(linear, 10.0), 3.0);
+
+        let power = MouseAcceleration::Power {
+            rate: 0.5,
+            exponent: 2.0,
+            cap: 4.0,
+        };
+        assert_eq!(mouse_acceleration(power, 0.0), 1.0);
+        assert_eq!(mouse_acceleration(power, 2.0), 2.0);
+        assert_eq!(mouse_acceleration(power, 4.0), 4.0);
+        assert_eq!(mouse_acceleration(power, 10.0), 4.0);
+
+        // sensitivity 5 with m_accel 0.5 is sensitivity 6 at 2 counts per millisecond
+        let quake = MouseAcceleration::Quake {
+            accel: 0.5,
+            sensitivity: 5.0,
+        };
+        assert_eq!(mouse_acceleration(quake, 0.0), 1.0);
+        assert!((mouse_acceleration(quake, 2.0) - 1.2).abs() < 1e-6);
+
+        assert_eq!(mouse_acceleration(MouseAcceleration::None, 10.0), 1.0);
+    }
+
+    #[test]
+    fn turns_off_quake_acceleration_without_sensitivity() {
+        let quake = |sensitivity| LookSettings {
+            acceleration: MouseAcceleration::Quake {
+                accel: 0.5,
+                sensitivity,
+            },
+            ..default()
+        };
+        let mut world = World::new();
+        let broken = world.spawn(quake(0.0)).id();
+        let working = world.spawn(quake(5.0)).id();
+        world.run_system_once(fps_controller_look_settings).unwrap();
+
+        let look = *world.get::<LookSettings>(broken).unwrap();
+        assert_eq!(look.acceleration, MouseAcceleration::None);
+        assert_eq!(
+            world.get::<LookSettings>(working).unwrap().acceleration,
+            quake(5.0).acceleration
+        );
+        let mut smoothed = *world.get::<SmoothedLook>(broken).unwrap();
+        let delta = mouse_look_delta(&look, &mut smoothed, Vec2::new(10.0, 0.0), 0.01);
+        assert!((delta.x - 0.01).abs() < 1e-6, "{delta}");
+    }
+
+    #[test]
+    fn accelerated_mouse_look_depends_on_speed() {
+        let mut smoothed = SmoothedLook::default();
+        let look = LookSettings {
+            sensitivity: Vec2::splat(0.001),
+            acceleration: MouseAcceleration::Linear {
+                rate: 1.0,
+                cap: 10.0,
+            },
+            ..default()
+        };
+        // The same 20 counts in one 10 ms frame is 2 counts per millisecond, over 100 ms it is 0.2
+        let fast = mouse_look_delta(&look, &mut smoothed, Vec2::new(20.0, 0.0), 0.01);
+        let slow = mouse_look_delta(&look, &mut smoothed, Vec2::new(20.0, 0.0), 0.1);
+        assert!((fast.x - 0.06).abs() < 1e-6, "{fast}");
+        assert!((slow.x - 0.024).abs() < 1e-6, "{slow}");
+    }
+
+    #[test]
+    fn smoothed_mouse_look_lags_but_turns_as_far() {
+        let mut smoothed = SmoothedLook::default();
+        let look = LookSettings {
+            sensitivity: Vec2::splat(0.001),
+            smoothing: 0.05,
+            ..default()
+        };
+        let dt = 1.0 / 120.0;
+        let first = mouse_look_delta(&look, &mut smoothed, Vec2::new(100.0, -50.0), dt);
+        assert!(first.x > 0.0 && first.x < 0.1 / 2.0, "{first}");
+        let mut total = first;
+        for _ in 0..240 {
+            total += mouse_look_delta(&look, &mut smoothed, Vec2::ZERO, dt);
+        }
+        assert!((total - Vec2::new(0.1, -0.05)).length() < 1e-4, "{total}");
+    }
+
+    #[test]
+    fn stick_response_skips_deadzone_and_curves() {
+        assert_eq!(stick_response(Vec2::new(0.1, 0.1), 0.2, 2.0), Vec2::ZERO);
+        // Just past the deadzone starts from zero, full deflection reaches one
+        assert!(stick_response(Vec2::new(0.21, 0.0), 0.2, 2.0).length() < 1e-3);
+        assert!((stick_response(Vec2::new(0.0, -1.0), 0.2, 2.0) - Vec2::NEG_Y).length() < 1e-6);
+        // Halfway through the live range, squared
+        let half = stick_response(Vec2::new(0.6, 0.0), 0.2, 2.0);
+        assert!((half - Vec2::new(0.25, 0.0)).length() < 1e-6, "{half}");
+        // Diagonals are no faster than straight pushes
+        let diagonal = stick_response(Vec2::ONE, 0.2, 1.0);
+        assert!((diagonal.length() - 1.0).abs() < 1e-6, "{diagonal}");
+    }
+
+    #[test]
+    fn stick_look_accelerates_at_the_edge() {
+        let mut look = GamepadLook {
+            sensitivity: 2.0,
+            deadzone: 0.0,
+            response_exponent: 1.0,
+            acceleration: Some(AimAcceleration {
+                threshold: 0.9,
+                max_multiplier: 3.0,
+                ramp_time: 1.0,
+            }),
+            acceleration_timer: 0.0,
+        };
+        let dt = 0.1;
+        // Below the threshold turns at a constant speed
+        for _ in 0..20 {
+            let delta = stick_look_delta(&mut look, Vec2::new(0.5, 0.0), dt);
+            assert!((delta.x - 0.1).abs() < 1e-6, "{delta}");
+        }
+        // At the edge it builds up to the maximum over the ramp time and stays there
+        let mut deltas = Vec::new();
+        for _ in 0..20 {
+            deltas.push(stick_look_delta(&mut look, Vec2::X, dt).x);
+        }
+        assert!((deltas[0] - 0.24).abs() < 1e-5, "{deltas:?}");
+        assert!(deltas.windows(2).all(|pair| pair[1] >= pair[0]));
+        assert!((deltas[19] - 0.6).abs() < 1e-5, "{deltas:?}");
+        // Letting go resets it
+        stick_look_delta(&mut look, Vec2::ZERO, dt);
+        assert_eq!(stick_look_delta(&mut look, Vec2::X, dt).x, deltas[0]);
+    }
+}
@@ -0,0 +1,920 @@
+use crate::{
+    util::controller_filter, Checkpoint, FpsControllerInput, KillPlane, KillVolume, Ladder,
+    LevelCompleted, LevelExit, LevelRegistry, LevelScene, LevelState, LoadLevel, LogicalPlayer,
+    MainScene, RespawnPoint, SpawnPoint, TriggerVolume, Water, CHECKPOINT_LAYER, KILL_LAYER,
+    LADDER_LAYER, TRIGGER_LAYER, WATER_LAYER,
+};
+use avian3d::prelude::*;
+use bevy::{
+    gltf::{Gltf, GltfExtras},
+    prelude::*,
+    render::mesh::MeshAabb,
+    scene::SceneInstanceReady,
+    transform::helper::TransformHelper,
+};
+
+/// Spawns the default scene of the [`MainScene`] once it has loaded, as a [`LevelScene`].
+///
+/// Each node of a level gets physics according to the keywords at the end of its name, e.g.
+/// "Crate-convex-dynamic", or the `collider` and `dynamic` glTF extras of the node, e.g.
+/// `{"collider": "convex", "dynamic": true}`, which take precedence:
+///
+/// - `col`: a triangle mesh collider, the default
+/// - `convex`: a convex hull collider
+/// - `decomp`: a convex decomposition of the mesh
+/// - `nocol`: no collider, the node is only rendered
+/// - `trigger`: a [`TriggerVolume`]
+/// - `exit`: a [`LevelExit`]
+/// - `water`, `checkpoint` and `kill`: a [`Water`], [`Checkpoint`] or [`KillVolume`] volume
+/// - `ladder`: a triangle mesh collider with a [`Ladder`] volume around it
+/// - `dynamic`: a dynamic rigid body instead of a static one, with a convex hull collider unless
+///   given another one
+///
+/// Empties named like "Start-spawn" become [`SpawnPoint`]s, and one named like "Floor-killplane"
+/// sets the height of the [`KillPlane`].
+///
+/// When the glTF changes on disk, which needs the `bevy/file_watcher` feature, the level is
+/// rebuilt. Players stay where they were unless that is inside the new geometry, in which case
+/// they are moved to their [`RespawnPoint`], i.e. the spawn point of the level if it has one.
+pub fn scene_colliders(
+    mut commands: Commands,
+    mut main_scene: ResMut<MainScene>,
+    gltf_assets: Res<Assets<Gltf>>,
+    mut gltf_events: EventReader<AssetEvent<Gltf>>,
+    levels: Query<Entity, With<LevelScene>>,
+    players: LevelPlayerQuery,
+) {
+    let modified = gltf_events
+        .read()
+        .any(|event| event.is_modified(&main_scene.handle));
+    if modified && main_scene.is_loaded {
+        unload_level(&mut commands, &levels, &players, false);
+        main_scene.is_loaded = false;
+    }
+    if main_scene.is_loaded {
+        return;
+    }
+    let Some(gltf) = gltf_assets.get(&main_scene.handle) else {
+        return;
+    };
+    main_scene.is_loaded = true;
+    let Some(scene) = gltf
+        .default_scene
+        .clone()
+        .or_else(|| gltf.scenes.first().cloned())
+    else {
+        warn!("Level has no scene to spawn");
+        return;
+    };
+    commands.spawn((SceneRoot(scene), LevelScene));
+}
+
+/// Switches to the level of the last [`LoadLevel`] event, showing the [`LevelState::Loading`] state
+/// until it is ready. Players are held in place while it loads, then moved to its spawn point.
+pub fn load_level(
+    mut commands: Commands,
+    mut load_level: EventReader<LoadLevel>,
+    registry: Res<LevelRegistry>,
+    asset_server: Res<AssetServer>,
+    levels: Query<Entity, With<LevelScene>>,
+    players: LevelPlayerQuery,
+    mut next_state: ResMut<NextState<LevelState>>,
+) {
+    let Some(&LoadLevel { index }) = load_level.read().last() else {
+        return;
+    };
+    let Some(level) = registry.levels.get(index) else {
+        warn!("No level {index} in the registry");
+        return;
+    };
+    unload_level(&mut commands, &levels, &players, true);
+    commands.insert_resource(MainScene {
+        handle: asset_server.load(&level.path),
+        is_loaded: false,
+        level: index,
+    });
+    next_state.set(LevelState::Loading);
+}
+
+/// Finishes the [`LevelState::Loading`] state once the level is ready and no player is held in
+/// place anymore, e.g. still standing in the exit of the previous level
+pub(crate) fn level_loaded(
+    levels: Query<Has<Children>, With<LevelScene>>,
+    pending_colliders: Query<(), With<ColliderConstructor>>,
+    reloading: Query<(), With<LevelReload>>,
+    mut next_state: ResMut<NextState<LevelState>>,
+) {
+    if level_built(&levels, &pending_colliders) && reloading.is_empty() {
+        next_state.set(LevelState::Playing);
+    }
+}
+
+/// Loads the next level of the [`LevelRegistry`] when a player touches a [`LevelExit`], going back
+/// to the first one after the last. Only once, even if more ticks run before the level unloads.
+pub(crate) fn fps_controller_level_exit(
+    mut commands: Commands,
+    spatial_query: Res<SpatialQueryPipeline>,
+    players: Query<(Entity, &Collider, &Transform), With<LogicalPlayer>>,
+    exits: Query<(), With<LevelExit>>,
+    main_scene: Res<MainScene>,
+    registry: Res<LevelRegistry>,
+    mut next_state: ResMut<NextState<LevelState>>,
+) {
+    if matches!(*next_state, NextState::Pending(LevelState::Loading)) {
+        return;
+    }
+    let filter = SpatialQueryFilter::from_mask(TRIGGER_LAYER);
+    let exited = players.iter().find(|(_, collider, transform)| {
+        spatial_query
+            .shape_intersections(collider, transform.translation, transform.rotation, &filter)
+            .into_iter()
+            .any(|volume| exits.contains(volume))
+    });
+    if let Some((entity, ..)) = exited {
+        if !registry.levels.is_empty() {
+            commands.send_event(LevelCompleted {
+                entity,
+                level: main_scene.level,
+            });
+            commands.send_event(LoadLevel {
+                index: (main_scene.level + 1) % registry.levels.len(),
+            });
+            next_state.set(LevelState::Loading);
+        }
+    }
+}
+
+/// Despawns the current level, holding players in place until the next one is built. They are then
+/// moved to its spawn point if `to_spawn_point`, otherwise only if they would be inside of it.
+/// Moving to another level forgets the checkpoints of this one.
+fn unload_level(
+    commands: &mut Commands,
+    levels: &Query<Entity, With<LevelScene>>,
+    players: &LevelPlayerQuery,
+    to_spawn_point: bool,
+) {
+    for level in levels {
+        commands.entity(level).despawn();
+    }
+    // The new level sets it again if it has one
+    commands.insert_resource(KillPlane::default());
+    for (player, transform, velocity) in players {
+        if to_spawn_point {
+            commands.entity(player).insert(RespawnPoint::default());
+        }
+        commands.entity(player).insert(LevelReload {
+            translation: transform.translation,
+            velocity: velocity.0,
+            settled: false,
+            to_spawn_point,
+        });
+    }
+}
+
+/// Whether every level has been spawned and its colliders built. A level has children once its
+/// scene is spawned, and its colliders are built after that.
+fn level_built(
+    levels: &Query<Has<Children>, With<LevelScene>>,
+    pending_colliders: &Query<(), With<ColliderConstructor>>,
+) -> bool {
+    !levels.is_empty() && levels.iter().all(|spawned| spawned) && pending_colliders.is_empty()
+}
+
+/// Gives the nodes of a [`LevelScene`] that finished spawning their physics, see
+/// [`scene_colliders`]. The colliders are added to the spawned entities so that they follow the
+/// node hierarchy.
+pub(crate) fn level_scene_ready(
+    trigger: Trigger<SceneInstanceReady>,
+    mut commands: Commands,
+    levels: Query<(), With<LevelScene>>,
+    children: Query<&Children>,
+    entities: Query<(Option<&Name>, Option<&GltfExtras>, Option<&Mesh3d>)>,
+    meshes: Res<Assets<Mesh>>,
+    transform_helper: TransformHelper,
+) {
+    let level = trigger.target();
+    if !levels.contains(level) {
+        return;
+    }
+    for node in children.iter_descendants(level) {
+        // The glTF loader spawns the primitives of a mesh as children of its node
+        let Ok((Some(name), extras, None)) = entities.get(node) else {
+            continue;
+        };
+        let options = NodeOptions::new(name, extras);
+        if options.spawn_point {
+            commands.entity(node).insert(SpawnPoint);
+        }
+        if options.kill_plane {
+            if let Ok(transform) = transform_helper.compute_global_transform(node) {
+                commands.insert_resource(KillPlane(transform.translation().y));
+            }
+        }
+
+        let collider = options.collider();
+        let primitives = children
+            .get(node)
+            .into_iter()
+            .flat_map(|primitives| primitives.iter())
+            .filter_map(|primitive| {
+                let (_, _, mesh) = entities.get(primitive).ok()?;
+                Some((primitive, mesh?))
+            });
+        let mut solid = false;
+        for (primitive, mesh) in primitives {
+            let mut primitive = commands.entity(primitive);
+            match collider {
+                NodeCollider::Trimesh | NodeCollider::Ladder => {
+                    // Oriented, so that point queries can tell whether they are inside of it
+                    primitive.insert(ColliderConstructor::TrimeshFromMeshWithConfig(
+                        TrimeshFlags::FIX_INTERNAL_EDGES,
+                    ));
+                }
+                NodeCollider::ConvexHull => {
+                    primitive.insert(ColliderConstructor::ConvexHullFromMesh);
+                }
+                NodeCollider::ConvexDecomposition => {
+                    primitive.insert(ColliderConstructor::ConvexDecompositionFromMesh);
+                }
+                NodeCollider::Trigger => {
+                    primitive.insert((volume(TRIGGER_LAYER), TriggerVolume));
+                }
+                NodeCollider::Exit => {
+                    primitive.insert((volume(TRIGGER_LAYER), LevelExit));
+                }
+                NodeCollider::Water => {
+                    primitive.insert((volume(WATER_LAYER), Water));
+                }
+                NodeCollider::Checkpoint => {
+                    primitive.insert((volume(CHECKPOINT_LAYER), Checkpoint));
+                }
+                NodeCollider::Kill => {
+                    primitive.insert((volume(KILL_LAYER), KillVolume));
+                }
+                NodeCollider::None => {}
+            }
+            if collider == NodeCollider::Ladder {
+                match meshes.get(mesh) {
+                    Some(mesh) => {
+                        primitive.with_child(ladder_volume(mesh));
+                    }
+                    None => warn!("Ladder {name} has no mesh to make a volume from"),
+                }
+            }
+            solid |= collider.is_solid();
+        }
+        if solid {
+            commands.entity(node).insert(if options.dynamic {
+                RigidBody::Dynamic
+            } else {
+                RigidBody::Static
+            });
+        }
+    }
+}
+
+/// Where a player was when their level started reloading
+#[derive(Component)]
+#[component(storage = "SparseSet")]
+pub(crate) struct LevelReload {
+    translation: Vec3,
+    velocity: Vec3,
+    /// Whether the colliders of the new level have been through a physics step, and so can be
+    /// found by spatial queries
+    settled: bool,
+    /// Whether to move to the new level's spawn point even if not stuck, e.g. for another level
+    pub(crate) to_spawn_point: bool,
+}
+
+type LevelPlayerQuery<'w, 's> =
+    Query<'w, 's, (Entity, &'static Transform, &'static LinearVelocity), With<LogicalPlayer>>;
+
+type LevelReloadQuery<'w, 's> = Query<
+    'w,
+    's,
+    (
+        Entity,
+        &'static mut LevelReload,
+        &'static Collider,
+        &'static RespawnPoint,
+        &'static mut Transform,
+        &'static mut LinearVelocity,
+        &'static mut FpsControllerInput,
+    ),
+>;
+
+/// Holds players in place while their level reloads, then puts them back where they were, or at
+/// their [`RespawnPoint`] if that is now inside geometry or the level is a different one.
+pub(crate) fn fps_controller_level_reload(
+    mut commands: Commands,
+    spatial_query: Res<SpatialQueryPipeline>,
+    levels: Query<Has<Children>, With<LevelScene>>,
+    pending_colliders: Query<(), With<ColliderConstructor>>,
+    mut players: LevelReloadQuery,
+) {
+    let built = level_built(&levels, &pending_colliders);
+    for (entity, mut reload, collider, respawn_point, mut transform, mut velocity, mut input) in
+        &mut players
+    {
+        if !(built && reload.settled) {
+            reload.settled = built;
+            transform.translation = reload.translation;
+            velocity.0 = Vec3::ZERO;
+            continue;
+        }
+        commands.entity(entity).remove::<LevelReload>();
+
+        // Shrunk a little so that standing on the ground does not count. Only touches the surface
+        // of triangle meshes, which are hollow, so also check whether we are inside one.
+        let mut shape = collider.clone();
+        shape.set_scale(Vec3::splat(0.9), 10);
+        let filter = controller_filter(entity);
+        let stuck = reload.to_spawn_point
+            || !spatial_query
+                .shape_intersections(&shape, reload.translation, transform.rotation, &filter)
+                .is_empty()
+            || !spatial_query
+                .point_intersections(reload.translation, &filter)
+                .is_empty();
+        if stuck {
+            transform.translation = respawn_point.translation;
+            velocity.0 = Vec3::ZERO;
+            input.yaw = respawn_point.yaw;
+            input.pitch = respawn_point.pitch;
+        } else {
+            transform.translation = reload.translation;
+            velocity.0 = reload.velocity;
+        }
+    }
+}
+
+/// Sensor in the shape of a mesh, on its own collision layer so that the controller's ground and
+/// obstacle queries ignore it
+fn volume(layer: LayerMask) -> impl Bundle {
+    (
+        ColliderConstructor::ConvexHullFromMesh,
+        Sensor,
+        CollisionLayers::new(layer, LayerMask::ALL),
+    )
+}
+
+/// Sensor around a ladder mesh, reaching a little past it so that we can grab it and climb over
+/// the top of it
+fn ladder_volume(mesh: &Mesh) -> impl Bundle {
+    const MARGIN: f32 = 0.25;
+    let aabb = mesh.compute_aabb().unwrap_or_default();
+    let size = Vec3::from(aabb.half_extents) * 2.0 + Vec3::splat(MARGIN * 2.0);
+    (
+        Collider::cuboid(size.x, size.y, size.z),
+        Sensor,
+        Ladder,
+        CollisionLayers::new(LADDER_LAYER, LayerMask::ALL),
+        Transform::from_translation(aabb.center.into()),
+    )
+}
+
+/// What the meshes of a level node turn into
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum NodeCollider {
+    Trimesh,
+    ConvexHull,
+    ConvexDecomposition,
+    Trigger,
+    Exit,
+    Water,
+    Ladder,
+    Checkpoint,
+    Kill,
+    None,
+}
+
+impl NodeCollider {
+    /// Whether the collider blocks movement and so needs a rigid body
+    fn is_solid(self) -> bool {
+        matches!(
+            self,
+            Self::Trimesh | Self::ConvexHull | Self::ConvexDecomposition | Self::Ladder
+        )
+    }
+}
+
+/// Physics of a level node, from the keywords of its name and its glTF extras
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct NodeOptions {
+    collider: Option<NodeCollider>,
+    dynamic: bool,
+    spawn_point: bool,
+    kill_plane: bool,
+}
+
+impl NodeOptions {
+    fn new(name: &str, extras: Option<&GltfExtras>) -> Self {
+        let mut options = Self::default();
+        let name = name.to_lowercase();
+        // Blender numbers copies of a node like "Crate-dynamic.001"
+        let name = match name.rsplit_once('.') {
+            Some((name, number)) if number.chars().all(|c| c.is_ascii_digit()) => name,
+            _ => &name,
+        };
+        for keyword in name.split('-').skip(1) {
+            options.apply(keyword);
+        }
+
+        let extras =
+            extras.and_then(|extras| serde_json::from_str::<serde_json::Value>(&extras.value).ok());
+        if let Some(extras) = extras {
+            if let Some(collider) = extras.get("collider").and_then(|value| value.as_str()) {
+                if !options.apply(&collider.to_lowercase()) {
+                    warn!("Unknown collider \"{collider}\" in the extras of {name}");
+                }
+            }
+            if let Some(dynamic) = extras.get("dynamic").and_then(|value| value.as_bool()) {
+                options.dynamic = dynamic;
+            }
+        }
+        options
+    }
+
+    /// Applies a keyword, returning whether it was recognized
+    fn apply(&mut self, keyword: &str) -> bool {
+        let collider = match keyword {
+            "col" => NodeCollider::Trimesh,
+            "convex" => NodeCollider::ConvexHull,
+            "decomp" => NodeCollider::ConvexDecomposition,
+            "nocol" => NodeCollider::None,
+            "trigger" => NodeCollider::Trigger,
+            "exit" => NodeCollider::Exit,
+            "water" => NodeCollider::Water,
+            "ladder" => NodeCollider::Ladder,
+            "checkpoint" => NodeCollider::Checkpoint,
+            "kill" => NodeCollider::Kill,
+            "dynamic" => {
+                self.dynamic = true;
+                return true;
+            }
+            "spawn" => {
+                self.spawn_point = true;
+                return true;
+            }
+            "killplane" => {
+                self.kill_plane = true;
+                return true;
+            }
+            _ => return false,
+        };
+        self.collider = Some(collider);
+        true
+    }
+
+    fn collider(&self) -> NodeCollider {
+        // Dynamic triangle meshes are hollow and expensive, default to a convex hull instead
+        self.collider.unwrap_or(if self.dynamic {
+            NodeCollider::ConvexHull
+        } else {
+            NodeCollider::Trimesh
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{run, spawn_player, test_app_with, FLOOR_TOP};
+    use crate::{LevelInfo, LevelPlugin};
+    use bevy::{render::view::VisibilityClass, state::app::StatesPlugin, time::TimeUpdateStrategy};
+    use std::time::Duration;
+
+    fn options(name: &str, extras: Option<&str>) -> NodeOptions {
+        let extras = extras.map(|value| GltfExtras {
+            value: value.to_string(),
+        });
+        NodeOptions::new(name, extras.as_ref())
+    }
+
+    #[test]
+    fn reads_node_options_from_names_and_extras() {
+        assert_eq!(options("Floor", None).collider(), NodeCollider::Trimesh);
+        assert_eq!(options("Half-pipe", None).collider(), NodeCollider::Trimesh);
+        assert_eq!(
+            options("Rock-Convex", None).collider(),
+            NodeCollider::ConvexHull
+        );
+        assert_eq!(
+            options("Pool-water.001", None).collider(),
+            NodeCollider::Water
+        );
+        let crate_ = options("Crate-dynamic", None);
+        assert!(crate_.dynamic);
+        assert_eq!(crate_.collider(), NodeCollider::ConvexHull);
+        let statue = options("Statue-decomp-dynamic", None);
+        assert!(statue.dynamic);
+        assert_eq!(statue.collider(), NodeCollider::ConvexDecomposition);
+        assert_eq!(options("Door-exit", None).collider(), NodeCollider::Exit);
+        assert!(options("Start-spawn", None).spawn_point);
+        assert!(options("Floor-killplane", None).kill_plane);
+
+        // Extras take precedence over the name
+        let zone = options(
+            "Zone-nocol",
+            Some(r#"{"collider": "trigger", "dynamic": false}"#),
+        );
+        assert_eq!(zone.collider(), NodeCollider::Trigger);
+        assert!(!zone.dynamic);
+        let barrel = options("Barrel", Some(r#"{"dynamic": true}"#));
+        assert!(barrel.dynamic);
+        assert_eq!(barrel.collider(), NodeCollider::ConvexHull);
+        assert_eq!(
+            options("Wall-ladder", Some("not json")).collider(),
+            NodeCollider::Ladder
+        );
+    }
+
+    /// Spawns a level with the given nodes, each with a cuboid mesh of the given size if any
+    /// Name, glTF extras, size of a cuboid mesh if any, and transform of a level node
+    type LevelNode<'a> = (&'a str, Option<&'a str>, Option<Vec3>, Transform);
+
+    /// Scene laid out like the glTF loader does, with the given nodes
+    fn level_scene(app: &mut App, nodes: &[LevelNode]) -> Handle<Scene> {
+        // The components a scene spawns need to be reflected, the render plugins do that outside
+        // of tests
+        app.register_type::<Mesh3d>()
+            .register_type::<GltfExtras>()
+            .register_type::<Visibility>()
+            .register_type::<InheritedVisibility>()
+            .register_type::<ViewVisibility>()
+            .register_type::<VisibilityClass>();
+        let mut scene = World::new();
+        // Offset the whole level so that it only lines up if the hierarchy is taken into account
+        let root = scene.spawn(Transform::from_xyz(100.0, 0.0, 0.0)).id();
+        for &(name, extras, size, transform) in nodes {
+            let mut node = scene.spawn((Name::new(name.to_string()), transform, ChildOf(root)));
+            if let Some(extras) = extras {
+                node.insert(GltfExtras {
+                    value: extras.to_string(),
+                });
+            }
+            if let Some(size) = size {
+                let mesh = app
+                    .world_mut()
+                    .resource_mut::<Assets<Mesh>>()
+                    .add(Cuboid::from_size(size));
+                node.with_child((Name::new(format!("{name}.0")), Mesh3d(mesh)));
+            }
+        }
+        app.world_mut()
+            .resource_mut::<Assets<Scene>>()
+            .add(Scene::new(scene))
+    }
+
+    fn spawn_level(app: &mut App, nodes: &[LevelNode]) {
+        let scene = level_scene(app, nodes);
+        app.world_mut().spawn((SceneRoot(scene), LevelScene));
+    }
+
+    /// Entity of the mesh primitive of the node with the given name
+    fn primitive(app: &mut App, name: &str) -> Entity {
+        let name = format!("{name}.0");
+        app.world_mut()
+            .query::<(Entity, &Name)>()
+            .iter(app.world())
+            .find(|(_, primitive)| primitive.as_str() == name)
+            .unwrap()
+            .0
+    }
+
+    #[test]
+    fn builds_level_physics_from_nodes() {
+        let mut app = test_app_with((StatesPlugin, LevelPlugin));
+        spawn_level(
+            &mut app,
+            &[
+                (
+                    "Floor",
+                    None,
+                    Some(Vec3::new(20.0, 1.0, 20.0)),
+                    Transform::from_xyz(0.0, FLOOR_TOP - 0.5, 0.0),
+                ),
+                (
+                    "Crate-dynamic",
+                    None,
+                    Some(Vec3::ONE),
+                    Transform::from_xyz(3.0, FLOOR_TOP + 3.0, 0.0),
+                ),
+                (
+                    "Bush-nocol",
+                    None,
+                    Some(Vec3::ONE),
+                    Transform::from_xyz(-3.0, FLOOR_TOP + 0.5, 0.0),
+                ),
+                (
+                    "Zone",
+                    Some(r#"{"collider": "trigger"}"#),
+                    Some(Vec3::splat(2.0)),
+                    Transform::from_xyz(0.0, FLOOR_TOP + 1.0, -5.0),
+                ),
+                (
+                    "Start-spawn",
+                    None,
+                    None,
+                    Transform::from_xyz(0.0, FLOOR_TOP + 1.5, 5.0),
+                ),
+                (
+                    "Floor-killplane",
+                    None,
+                    None,
+                    Transform::from_xyz(0.0, -20.0, 0.0),
+                ),
+            ],
+        );
+        let player = spawn_player(&mut app, Vec3::new(100.0, FLOOR_TOP + 3.0, 0.0));
+        run(&mut app, 2.0);
+
+        // Standing on the floor where the level was moved to
+        let world = app.world();
+        let feet = world.get::<Position>(player).unwrap().y - 1.5;
+        assert!((feet - FLOOR_TOP).abs() < 0.05, "feet at {feet}");
+        assert_eq!(
+            world.get::<RespawnPoint>(player).unwrap().translation,
+            Vec3::new(100.0, FLOOR_TOP + 1.5, 5.0)
+        );
+        assert_eq!(world.resource::<KillPlane>().0, -20.0);
+
+        // The crate fell onto the floor
+        let crate_ = primitive(&mut app, "Crate-dynamic");
+        let world = app.world();
+        let crate_position = world.get::<GlobalTransform>(crate_).unwrap().translation();
+        assert!(
+            (crate_position - Vec3::new(103.0, FLOOR_TOP + 0.5, 0.0)).length() < 0.05,
+            "crate at {crate_position}"
+        );
+
+        let bush = primitive(&mut app, "Bush-nocol");
+        assert!(app.world().get::<Collider>(bush).is_none());
+        let zone = primitive(&mut app, "Zone");
+        let world = app.world();
+        assert!(world.get::<Collider>(zone).is_some());
+        assert!(world.get::<Sensor>(zone).is_some());
+        assert!(world.get::<TriggerVolume>(zone).is_some());
+    }
+
+    fn gltf(scene: Handle<Scene>) -> Gltf {
+        Gltf {
+            scenes: vec![scene.clone()],
+            named_scenes: default(),
+            meshes: default(),
+            named_meshes: default(),
+            materials: default(),
+            named_materials: default(),
+            nodes: default(),
+            named_nodes: default(),
+            skins: default(),
+            named_skins: default(),
+            default_scene: Some(scene),
+            animations: default(),
+            named_animations: default(),
+            source: None,
+        }
+    }
+
+    fn count_named(app: &mut App, name: &str) -> usize {
+        app.world_mut()
+            .query::<&Name>()
+            .iter(app.world())
+            .filter(|entity| entity.as_str() == name)
+            .count()
+    }
+
+    #[test]
+    fn rebuilds_level_when_gltf_changes() {
+        let mut app = test_app_with((StatesPlugin, LevelPlugin));
+        app.init_asset::<Gltf>();
+        let floor = (
+            "Floor",
+            None,
+            Some(Vec3::new(20.0, 1.0, 20.0)),
+            Transform::from_xyz(0.0, FLOOR_TOP - 0.5, 0.0),
+        );
+        let spawn = (
+            "Start-spawn",
+            None,
+            None,
+            Transform::from_xyz(0.0, FLOOR_TOP + 1.5, 5.0),
+        );
+        let scene = level_scene(&mut app, &[floor, spawn]);
+        let handle = app
+            .world_mut()
+            .resource_mut::<Assets<Gltf>>()
+            .add(gltf(scene));
+        app.insert_resource(MainScene {
+            handle: handle.clone(),
+            is_loaded: false,
+            level: 0,
+        });
+        let player = spawn_player(&mut app, Vec3::new(102.0, FLOOR_TOP + 1.5, 0.0));
+        run(&mut app, 1.0);
+
+        let reload = |app: &mut App, nodes: &[LevelNode]| {
+            let scene = level_scene(app, nodes);
+            app.world_mut()
+                .resource_mut::<Assets<Gltf>>()
+                .get_mut(&handle)
+                .unwrap()
+                .default_scene = Some(scene);
+            run(app, 1.0);
+        };
+        let position = |app: &App| app.world().get::<Position>(player).unwrap().0;
+
+        // A change elsewhere leaves the player standing where they were
+        let crate_ = (
+            "Crate",
+            None,
+            Some(Vec3::ONE),
+            Transform::from_xyz(-5.0, FLOOR_TOP + 0.5, 0.0),
+        );
+        let before = position(&app);
+        reload(&mut app, &[floor, spawn, crate_]);
+        assert_eq!(count_named(&mut app, "Floor.0"), 1);
+        assert_eq!(count_named(&mut app, "Crate.0"), 1);
+        let translation = position(&app);
+        assert!(
+            (translation - before).length() < 0.01,
+            "moved from {before} to {translation}"
+        );
+
+        // Geometry where they stand moves them to the checkpoint they reached, which the spawn point
+        // coming back doesn't replace
+        let checkpoint = RespawnPoint {
+            translation: Vec3::new(95.0, FLOOR_TOP + 1.5, -5.0),
+            yaw: 1.0,
+            pitch: 0.0,
+        };
+        *app.world_mut().get_mut::<RespawnPoint>(player).unwrap() = checkpoint;
+        let pillar = (
+            "Pillar",
+            None,
+            Some(Vec3::new(2.0, 4.0, 2.0)),
+            Transform::from_xyz(2.0, FLOOR_TOP + 2.0, 0.0),
+        );
+        reload(&mut app, &[floor, spawn, pillar]);
+        assert_eq!(count_named(&mut app, "Crate.0"), 0);
+        assert_eq!(
+            *app.world().get::<RespawnPoint>(player).unwrap(),
+            checkpoint
+        );
+        let translation = position(&app);
+        assert!(
+            (translation - checkpoint.translation).length() < 0.05,
+            "at {translation}"
+        );
+    }
+
+    #[test]
+    fn parses_level_registry() {
+        let registry = LevelRegistry::from_ron(include_str!("../assets/levels.ron")).unwrap();
+        assert_eq!(registry.levels[0].path, "playground.glb");
+        assert!(LevelRegistry::from_ron("(levels: [(name: \"Missing path\")])").is_err());
+    }
+
+    /// Scenes of the levels in the registry, standing in for their glTF files
+    #[derive(Resource)]
+    struct LevelScenes(Vec<Handle<Scene>>);
+
+    #[derive(Resource, Default)]
+    struct Completions(usize);
+
+    #[test]
+    fn loads_levels_and_exits_to_the_next_one() {
+        let mut app = test_app_with((StatesPlugin, LevelPlugin));
+        app.init_asset::<Gltf>()
+            .insert_resource(LevelRegistry {
+                levels: ["first", "second", "third"]
+                    .map(|name| LevelInfo {
+                        name: name.to_string(),
+                        path: format!("{name}.glb"),
+                    })
+                    .into(),
+            })
+            .init_resource::<Completions>()
+            .add_systems(
+                Update,
+                |mut completed: EventReader<LevelCompleted>,
+                 mut completions: ResMut<Completions>| {
+                    completions.0 += completed.read().count();
+                },
+            )
+            .add_systems(
+                Update,
+                |main_scene: Option<Res<MainScene>>,
+                 scenes: Res<LevelScenes>,
+                 mut gltfs: ResMut<Assets<Gltf>>| {
+                    if let Some(main_scene) = main_scene {
+                        if !gltfs.contains(&main_scene.handle) {
+                            let scene = scenes.0[main_scene.level].clone();
+                            gltfs.insert(&main_scene.handle, gltf(scene));
+                        }
+                    }
+                },
+            );
+        let floor = (
+            "Floor",
+            None,
+            Some(Vec3::new(20.0, 1.0, 40.0)),
+            Transform::from_xyz(0.0, FLOOR_TOP - 0.5, 0.0),
+        );
+        let first = level_scene(
+            &mut app,
+            &[
+                floor,
+                (
+                    "Start-spawn",
+                    None,
+                    None,
+                    Transform::from_xyz(0.0, FLOOR_TOP + 1.5, 5.0),
+                ),
+                (
+                    "Door-exit",
+                    None,
+                    Some(Vec3::new(4.0, 3.0, 1.0)),
+                    Transform::from_xyz(0.0, FLOOR_TOP + 1.5, -5.0),
+                ),
+            ],
+        );
+        let second = level_scene(
+            &mut app,
+            &[
+                floor,
+                (
+                    "Start-spawn",
+                    None,
+                    None,
+                    Transform::from_xyz(-5.0, FLOOR_TOP + 1.5, 10.0),
+                ),
+            ],
+        );
+        // Without a spawn point
+        let third = level_scene(&mut app, &[floor]);
+        app.insert_resource(LevelScenes(vec![first, second, third]));
+        let player = spawn_player(&mut app, Vec3::new(0.0, FLOOR_TOP + 10.0, 0.0));
+        let state = |app: &App| *app.world().resource::<State<LevelState>>().get();
+        let position = |app: &App| app.world().get::<Position>(player).unwrap().0;
+
+        app.world_mut().send_event(LoadLevel { index: 0 });
+        app.update();
+        assert_eq!(state(&app), LevelState::Loading);
+        run(&mut app, 1.0);
+        assert_eq!(state(&app), LevelState::Playing);
+        let translation = position(&app);
+        assert!(
+            (translation - Vec3::new(100.0, FLOOR_TOP + 1.5, 5.0)).length() < 0.05,
+            "at {translation}"
+        );
+
+        // Walk through the exit, with a few ticks per frame that all touch it
+        app.insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f32(
+            3.0 / 64.0,
+        )));
+        app.world_mut()
+            .get_mut::<FpsControllerInput>(player)
+            .unwrap()
+            .movement = Vec3::Z;
+        for _ in 0..180 {
+            app.update();
+            if app.world().resource::<MainScene>().level == 1 {
+                break;
+            }
+        }
+        assert_eq!(app.world().resource::<MainScene>().level, 1);
+        app.insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f32(
+            1.0 / 60.0,
+        )));
+        app.world_mut()
+            .get_mut::<FpsControllerInput>(player)
+            .unwrap()
+            .movement = Vec3::ZERO;
+        run(&mut app, 1.0);
+        assert_eq!(app.world().resource::<Completions>().0, 1);
+
+        assert_eq!(state(&app), LevelState::Playing);
+        assert_eq!(count_named(&mut app, "Door-exit.0"), 0);
+        assert_eq!(count_named(&mut app, "Floor.0"), 1);
+        let translation = position(&app);
+        assert!(
+            (translation - Vec3::new(95.0, FLOOR_TOP + 1.5, 10.0)).length() < 0.05,
+            "at {translation}"
+        );
+
+        // A checkpoint of this level means nothing in the next one
+        *app.world_mut().get_mut::<RespawnPoint>(player).unwrap() = RespawnPoint {
+            translation: Vec3::new(95.0, FLOOR_TOP + 1.5, -10.0),
+            yaw: 1.0,
+            pitch: 0.0,
+        };
+        app.world_mut().send_event(LoadLevel { index: 2 });
+        run(&mut app, 1.0);
+        assert_eq!(state(&app), LevelState::Playing);
+        assert_eq!(
+            *app.world().get::<RespawnPoint>(player).unwrap(),
+            RespawnPoint::default()
+        );
+    }
+}
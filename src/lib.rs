@@ -0,0 +1,27 @@
+mod bindings;
+mod components;
+mod demo;
+mod events;
+mod ghost;
+mod input;
+mod level;
+mod movement;
+mod player;
+mod plugin;
+mod render;
+mod respawn;
+#[cfg(test)]
+mod test_util;
+mod util;
+
+pub use bindings::{Binding, Bindings, BindingsFile, Rebinding};
+pub use components::*;
+pub use demo::{ControllerState, Demo, DemoPlayback, DemoRecorder};
+pub use events::*;
+pub use ghost::{
+    BestGhost, Ghost, GhostDirectory, GhostPose, GhostRecorder, GhostReplay, GHOST_VERSION,
+};
+pub use level::{load_level, scene_colliders};
+pub use player::*;
+pub use plugin::{BindingsPlugin, FpsControllerPlugin, GhostPlugin, LevelPlugin};
+pub use util::{display_text, manage_cursor};
@@ -1,18 +1,6 @@
-mod components;
-mod input;
-mod movement;
-mod plugin;
-mod render;
-mod util;
-
-use std::f32::consts::TAU;
-
-use avian3d::{math::Quaternion, prelude::*};
+use avian3d::prelude::*;
 use bevy::{prelude::*, render::camera::Exposure};
-use components::*;
-use leafwing_input_manager::prelude::*;
-use plugin::FpsControllerPlugin;
-use util::*;
+use bevy_game::*;
 
 const SPAWN_POINT: Vec3 = Vec3::new(0.0, 5.0, 0.0);
 
@@ -26,26 +14,47 @@ fn main() {
         .insert_resource(ClearColor(Color::Srgba(Srgba::hex("D4F5F5").unwrap())))
         .add_plugins(PhysicsPlugins::default())
         .add_plugins(DefaultPlugins)
-        .add_plugins(InputManagerPlugin::<FpsActions>::default())
-        .add_plugins(FpsControllerPlugin)
-        .add_systems(Startup, setup)
-        .add_systems(
-            Update,
-            (manage_cursor, scene_colliders, display_text, respawn),
+        .add_plugins(FpsControllerPlugin::default())
+        .add_plugins(LevelPlugin)
+        .add_plugins(BindingsPlugin::default())
+        .add_plugins(GhostPlugin::default())
+        .insert_resource(
+            LevelRegistry::from_ron(include_str!("../assets/levels.ron"))
+                .expect("levels.ron should be a valid level registry"),
         )
+        .add_systems(Startup, setup)
+        .add_systems(Update, (display_text, start_rebind, rebind_prompt))
+        .add_systems(OnEnter(LevelState::Loading), show_loading)
+        .add_systems(OnExit(LevelState::Loading), hide_loading)
         .run();
 }
 
+#[derive(Component)]
+struct LoadingText;
+
+#[derive(Component)]
+struct RebindText;
+
+/// Actions rebound by pressing F1 to F8
+const REBINDABLE: [(KeyCode, FpsActions); 8] = [
+    (KeyCode::F1, FpsActions::Forward),
+    (KeyCode::F2, FpsActions::Backward),
+    (KeyCode::F3, FpsActions::Left),
+    (KeyCode::F4, FpsActions::Right),
+    (KeyCode::F5, FpsActions::Jump),
+    (KeyCode::F6, FpsActions::Sprint),
+    (KeyCode::F7, FpsActions::Crouch),
+    (KeyCode::F8, FpsActions::Fly),
+];
+
 fn setup(mut commands: Commands, mut window: Query<&mut Window>, assets: Res<AssetServer>) {
     let Ok(mut window) = window.single_mut() else {
         return;
     };
     window.title = String::from("Game");
 
-    commands.insert_resource(MainScene {
-        handle: assets.load("playground.glb"),
-        is_loaded: false,
-    });
+    // Run with `--features bevy/file_watcher` to rebuild the level whenever it is re-exported
+    commands.send_event(LoadLevel { index: 0 });
 
     commands.spawn((
         DirectionalLight {
@@ -56,85 +65,44 @@ fn setup(mut commands: Commands, mut window: Query<&mut Window>, assets: Res<Ass
         Transform::from_xyz(4.0, 7.0, 5.0).looking_at(Vec3::ZERO, Vec3::Y),
     ));
 
-    // Rust analyzer complains if I put the mouse motion with the key bindings in one array in the constructor
-    // So I'm doing it in two steps
-    let mut input_map = InputMap::default();
-    input_map.insert_dual_axis(FpsActions::MousePosition, MouseMove::default());
-    input_map.insert_multiple([
-        (FpsActions::Forward, KeyCode::KeyW),
-        (FpsActions::Backward, KeyCode::KeyS),
-        (FpsActions::Left, KeyCode::KeyA),
-        (FpsActions::Right, KeyCode::KeyD),
-        (FpsActions::Sprint, KeyCode::ShiftLeft),
-        (FpsActions::Crouch, KeyCode::ControlLeft),
-        (FpsActions::Jump, KeyCode::Space),
-        (FpsActions::Fly, KeyCode::AltLeft),
-    ]);
-
-    let logical_entity_collider = Collider::capsule(1.0, 0.5);
-
     // Note that we have two entities for the player
     // One is a "logical" player that handles the physics computation and collision
     // The other is a "render" player that is what is displayed to the user
-    let logical_entity = commands
-        .spawn((
-            logical_entity_collider.clone(),
-            Friction::ZERO.with_combine_rule(CoefficientCombine::Min),
-            Restitution::ZERO.with_combine_rule(CoefficientCombine::Min),
-            LinearVelocity::ZERO,
-            RigidBody::Dynamic,
-            LockedAxes::ROTATION_LOCKED,
-            Mass(1.0),
-            GravityScale(0.0),
-            Transform::from_translation(SPAWN_POINT),
-            LogicalPlayer,
-            FpsControllerInput {
-                pitch: -TAU / 12.0,
-                yaw: TAU * 5.0 / 8.0,
-                ..default()
-            },
-            FpsController {
-                air_acceleration: 80.0,
-                ..default()
-            },
-        ))
-        .insert(CameraConfig {
-            height_offset: 0.0,
-            radius_scale: 0.75,
+    let player = FpsPlayerBundle::new(SPAWN_POINT)
+        .with_controller(FpsController {
+            air_acceleration: 80.0,
+            ..default()
         })
-        .insert(input_map)
-        .id();
-
-    // Capsule cast downwards to find ground
-    // Better than a ray cast as it handles when you are near the edge of a surface
-    let filter = SpatialQueryFilter::default().with_excluded_entities([logical_entity]);
-    let mut cast_capsule = logical_entity_collider.clone();
-    cast_capsule.set_scale(Vec3::ONE * 0.99, 10);
-    let shape_caster = ShapeCaster::new(
-        cast_capsule,
-        SPAWN_POINT,
-        Quaternion::default(),
-        Dir3::NEG_Y,
-    )
-    .with_query_filter(filter)
-    .with_max_hits(10)
-    .with_max_distance(6.0);
-
-    commands.entity(logical_entity).insert(shape_caster);
+        .spawn(&mut commands);
+    commands
+        .entity(player.render_entity)
+        .insert(Exposure::SUNLIGHT);
+    commands.entity(player.logical_entity).insert((
+        WallRunConfig::default(),
+        Health::new(100.0),
+        FallDamage::default(),
+    ));
 
     commands.spawn((
-        Camera3d::default(),
-        Projection::Perspective(PerspectiveProjection {
-            fov: TAU / 5.0,
+        DebugText,
+        Text::new(""),
+        TextFont {
+            font: assets.load("fira_mono.ttf"),
+            font_size: 24.0,
             ..default()
-        }),
-        Transform::default(),
-        Exposure::SUNLIGHT,
-        RenderPlayer { logical_entity },
+        },
+        TextColor(Color::BLACK),
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Px(5.0),
+            left: Val::Px(5.0),
+            ..default()
+        },
     ));
 
     commands.spawn((
-        Text::new(""),
+        RebindText,
+        Text::new("F1-F8 to rebind"),
         TextFont {
             font: assets.load("fira_mono.ttf"),
             font_size: 24.0,
@@ -143,22 +111,69 @@ fn setup(mut commands: Commands, mut window: Query<&mut Window>, assets: Res<Ass
         TextColor(Color::BLACK),
         Node {
             position_type: PositionType::Absolute,
-            top: Val::Px(5.0),
+            bottom: Val::Px(5.0),
             left: Val::Px(5.0),
             ..default()
         },
     ));
 }
 
-fn respawn(mut query: Query<(&mut Transform, &mut LinearVelocity)>) {
-    // println!("Respawning");
-    for (mut transform, mut velocity) in &mut query {
-        if transform.translation.y > -50.0 {
-            continue;
+fn start_rebind(keys: Res<ButtonInput<KeyCode>>, mut start_rebind: EventWriter<StartRebind>) {
+    for (key, action) in REBINDABLE {
+        if keys.just_pressed(key) {
+            start_rebind.write(StartRebind { action });
         }
+    }
+}
+
+fn rebind_prompt(
+    rebinding: Res<Rebinding>,
+    mut rebound: EventReader<Rebound>,
+    mut text: Query<&mut Text, With<RebindText>>,
+) {
+    let Ok(mut text) = text.single_mut() else {
+        return;
+    };
+    if let Some(Rebound {
+        action,
+        binding,
+        conflicts,
+    }) = rebound.read().last()
+    {
+        **text = format!("Bound {action:?} to {binding:?}");
+        if !conflicts.is_empty() {
+            text.push_str(&format!(", unbound it from {conflicts:?}"));
+        }
+    } else if rebinding.is_changed() {
+        **text = match rebinding.0 {
+            Some(action) => format!("Press a key for {action:?}, escape to cancel"),
+            None => String::from("F1-F8 to rebind"),
+        };
+    }
+}
+
+fn show_loading(mut commands: Commands, assets: Res<AssetServer>) {
+    commands.spawn((
+        LoadingText,
+        Text::new("Loading..."),
+        TextFont {
+            font: assets.load("fira_mono.ttf"),
+            font_size: 48.0,
+            ..default()
+        },
+        TextColor(Color::BLACK),
+        Node {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(20.0),
+            right: Val::Px(20.0),
+            ..default()
+        },
+    ));
+}
 
-        *velocity = LinearVelocity::ZERO;
-        transform.translation = SPAWN_POINT;
+fn hide_loading(mut commands: Commands, query: Query<Entity, With<LoadingText>>) {
+    for entity in &query {
+        commands.entity(entity).despawn();
     }
 }
 
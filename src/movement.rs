@@ -1,31 +1,85 @@
 use super::components::*;
-use super::util::acceleration;
+use super::events::*;
+use super::util::{acceleration, clip_velocity, controller_filter, overhang_component};
 use avian3d::{math::*, prelude::*};
 use bevy::prelude::*;
 
+const STEP_TOLERANCE: f32 = 0.01;
+/// Moving away from the ground faster than this means we are leaving it, e.g. jumping
+const LEAVE_GROUND_SPEED: f32 = 1.0;
+/// Ground further below our feet than this is not stood on, we fall towards it instead
+const GROUND_GAP: f32 = 0.1;
+/// Start swimming once this much of the controller is under water, e.g. from the waist down
+const SWIM_SUBMERSION: f32 = 0.5;
+/// Jumping while less than this much of the controller is under water jumps out of it
+const SURFACE_SUBMERSION: f32 = 0.8;
+/// Looking further down than this turns climbing up a ladder into climbing down it
+const CLIMB_DOWN_PITCH: f32 = -std::f32::consts::FRAC_PI_8;
+/// Speed we press against a ladder at while climbing it
+const LADDER_STICK_SPEED: f32 = 1.0;
+/// Seconds after jumping off a ladder before we can grab one again
+const LADDER_REGRAB_TIME: f32 = 0.5;
+/// Speed we press against a wall at while running on it
+const WALL_STICK_SPEED: f32 = 1.0;
+/// Fraction of a mantle spent climbing up the ledge, the rest is spent moving onto it
+const MANTLE_RISE: f32 = 0.6;
+
 // Type alias to reduce complexity
 type FpsControllerQuery<'w, 's> = Query<
     'w,
     's,
     (
         Entity,
-        &'static FpsControllerInput,
+        &'static mut FpsControllerInput,
         &'static mut FpsController,
+        &'static mut FpsControllerState,
         &'static mut Collider,
         &'static mut Transform,
         &'static mut LinearVelocity,
         &'static ShapeCaster,
         &'static ShapeHits,
+        Option<&'static WallRunConfig>,
+    ),
+>;
+
+type GroundedQuery<'w, 's> = Query<
+    'w,
+    's,
+    (
+        Entity,
+        &'static FpsController,
+        &'static ShapeHits,
+        &'static Position,
+        &'static Rotation,
+        &'static LinearVelocity,
         Has<Grounded>,
     ),
 >;
 
+type GroundBodyQuery<'w, 's> = Query<
+    'w,
+    's,
+    (
+        &'static LinearVelocity,
+        &'static AngularVelocity,
+        &'static Position,
+        &'static Rotation,
+        &'static ComputedCenterOfMass,
+    ),
+    Without<FpsController>,
+>;
+
+type VolumeQuery<'w, 's> = Query<'w, 's, &'static ColliderAabb, Or<(With<Water>, With<Ladder>)>>;
+
 // Struct to group ground mode parameters
-struct GroundModeParams<'a> {
+struct GroundModeParams<'a, 'w, 's> {
+    commands: &'a mut Commands<'w, 's>,
     entity: Entity,
+    spatial_query: &'a SpatialQueryPipeline,
     dt: f32,
     input: &'a FpsControllerInput,
     controller: &'a mut FpsController,
+    state: &'a mut FpsControllerState,
     collider: &'a mut Collider,
     transform: &'a mut Transform,
     velocity: &'a mut LinearVelocity,
@@ -33,22 +87,84 @@ struct GroundModeParams<'a> {
     grounded: bool,
 }
 
+// Struct to group wall run mode parameters
+struct WallRunModeParams<'a, 'w, 's> {
+    commands: &'a mut Commands<'w, 's>,
+    entity: Entity,
+    dt: f32,
+    input: &'a FpsControllerInput,
+    controller: &'a FpsController,
+    state: &'a mut FpsControllerState,
+    config: &'a WallRunConfig,
+    velocity: &'a mut LinearVelocity,
+    /// Horizontal normal of the wall we are running on
+    normal: Vec3,
+    /// Whether we just got onto the wall
+    starting: bool,
+}
+
+/// Whether a hit of the ground caster has a normal that isn't too steep to stand on
+fn is_ground(rotation: Quat, hit: &ShapeHitData) -> bool {
+    (rotation * -hit.normal2).angle_between(Vector::Y).abs() <= 0.5
+}
+
+/// Distance between the bottom of a controller with its lower sphere at `lower_sphere` and the
+/// ground it hit, along the normal of the ground. For flat ground this is simply the height of our
+/// feet above it.
+fn ground_gap(lower_sphere: Vec3, radius: f32, hit: &ShapeHitData) -> f32 {
+    Vec3::dot(lower_sphere - hit.point1, hit.normal1) - radius
+}
+
 /// Updates the [`Grounded`] status for character controllers.
-pub fn fps_controller_grounded(
-    mut commands: Commands,
-    mut query: Query<(Entity, &ShapeHits, &Rotation), With<FpsController>>,
-) {
-    for (entity, hits, rotation) in &mut query {
+pub fn fps_controller_grounded(time: Res<Time>, mut commands: Commands, mut query: GroundedQuery) {
+    let dt = time.delta_secs();
+    for (entity, controller, hits, position, rotation, velocity, was_grounded) in &mut query {
         // The character is grounded if the shape caster has a hit with a normal
-        // that isn't too steep.
-        let is_grounded = hits
-            .iter()
-            .any(|hit| (rotation * -hit.normal2).angle_between(Vector::Y).abs() <= 0.5);
+        // that isn't too steep, which we touch or reach this tick. The caster reaches further
+        // down than that, so we don't land while still falling towards the ground.
+        let lower_sphere = position.0 - Vector::Y * controller.height / 2.0;
+        let mut reach = GROUND_GAP + f32::max(-velocity.y, 0.0) * dt;
+        // Unless we are leaving the ground, stepping down snaps us onto it from a bit higher up
+        if velocity.y <= LEAVE_GROUND_SPEED {
+            reach = f32::max(reach, controller.step_offset);
+        }
+        let is_grounded = hits.iter().any(|hit| {
+            is_ground(rotation.0, hit) && ground_gap(lower_sphere, controller.radius, hit) < reach
+        });
 
-        if is_grounded {
+        if is_grounded && !was_grounded {
             commands.entity(entity).insert(Grounded);
-        } else {
+            commands.send_event(Landed {
+                entity,
+                impact_speed: f32::max(-velocity.y, 0.0),
+            });
+        } else if !is_grounded && was_grounded {
             commands.entity(entity).remove::<Grounded>();
+            commands.send_event(LeftGround { entity });
+        }
+    }
+}
+
+/// Hurts controllers with [`FallDamage`] when they land, based on how fast they hit the ground.
+/// Flying into it in noclip doesn't hurt.
+pub fn fps_controller_fall_damage(
+    mut commands: Commands,
+    mut landed: EventReader<Landed>,
+    mut query: Query<(&FpsController, &FallDamage, &mut Health)>,
+) {
+    for event in landed.read() {
+        let Ok((controller, fall_damage, mut health)) = query.get_mut(event.entity) else {
+            continue;
+        };
+        let severity = fall_damage.severity(event.impact_speed);
+        if controller.move_mode != MoveMode::Ground || severity <= 0.0 {
+            continue;
+        }
+        let max = health.max;
+        if health.damage(severity * max) {
+            commands.send_event(Died {
+                entity: event.entity,
+            });
         }
     }
 }
@@ -60,26 +176,115 @@ pub fn fps_controller_look(mut query: Query<(&mut FpsController, &FpsControllerI
     }
 }
 
-pub fn fps_controller_move(time: Res<Time>, mut query: FpsControllerQuery) {
+pub fn fps_controller_move(
+    mut commands: Commands,
+    time: Res<Time>,
+    spatial_query: Res<SpatialQueryPipeline>,
+    mut query: FpsControllerQuery,
+    collider_query: Query<&ColliderOf>,
+    ground_query: GroundBodyQuery,
+    volume_query: VolumeQuery,
+) {
     let dt = time.delta_secs();
 
     for (
         entity,
-        input,
+        mut input,
         mut controller,
+        mut state,
         mut collider,
         mut transform,
         mut velocity,
         _shape_caster,
         shape_hits,
-        grounded,
+        wall_run,
     ) in query.iter_mut()
     {
-        if input.fly {
-            controller.move_mode = match controller.move_mode {
-                MoveMode::Noclip => MoveMode::Ground,
-                MoveMode::Ground => MoveMode::Noclip,
+        // Ground within reach of the ground caster, even if we are still falling towards it
+        let grounded = shape_hits
+            .iter()
+            .any(|hit| is_ground(transform.rotation, hit));
+        let submersion = submersion(
+            &spatial_query,
+            &volume_query,
+            entity,
+            &controller,
+            &collider,
+            &transform,
+        );
+        let ladder = ladder_direction(&spatial_query, &volume_query, entity, &collider, &transform);
+        state.ladder_cooldown = f32::max(state.ladder_cooldown - dt, 0.0);
+        // Step off the bottom of a ladder rather than climbing down into the ground
+        let climbing = ladder.is_some()
+            && state.ladder_cooldown <= 0.0
+            && (!grounded || climb_direction(&input) > 0.0);
+        let wall = wall_run.and_then(|config| {
+            wall_normal(
+                &spatial_query,
+                entity,
+                &input,
+                &controller,
+                config,
+                &transform,
+                &velocity,
+            )
+        });
+        if grounded {
+            state.wall_run_timer = wall_run.map_or(0.0, |config| config.duration);
+        }
+        let wall_running = wall.is_some() && !grounded && state.wall_run_timer > 0.0;
+        // Catch a ledge we are jumping or falling towards
+        if controller.move_mode == MoveMode::Ground
+            && !grounded
+            && state.mantle_timer <= 0.0
+            && input.movement.z > 0.0
+        {
+            if let Some(target) = mantle_target(
+                &spatial_query,
+                entity,
+                &input,
+                &controller,
+                &collider,
+                &transform,
+            ) {
+                state.mantle_timer = controller.mantle_duration;
+                state.mantle_start = transform.translation;
+                state.mantle_target = target;
+            }
+        }
+        let volume_mode = if state.mantle_timer > 0.0 {
+            MoveMode::Mantle
+        } else if climbing {
+            MoveMode::Climb
+        } else if submersion >= SWIM_SUBMERSION {
+            MoveMode::Swim
+        } else if wall_running {
+            MoveMode::WallRun
+        } else {
+            MoveMode::Ground
+        };
+        let move_mode = match controller.move_mode {
+            MoveMode::Noclip if input.fly => volume_mode,
+            MoveMode::Noclip => MoveMode::Noclip,
+            _ if input.fly => MoveMode::Noclip,
+            _ => volume_mode,
+        };
+        let previous_mode = controller.move_mode;
+        if move_mode != controller.move_mode {
+            controller.move_mode = move_mode;
+            // Only the ground mode slides
+            if state.slide_timer > 0.0 {
+                state.slide_timer = 0.0;
+                commands.entity(entity).remove::<Sliding>();
+            }
+            // Flying off abandons a mantle
+            if move_mode != MoveMode::Mantle {
+                state.mantle_timer = 0.0;
             }
+            commands.send_event(ModeChanged {
+                entity,
+                mode: move_mode,
+            });
         }
 
         shape_hits.as_slice().iter().for_each(|hit| {
@@ -89,23 +294,342 @@ pub fn fps_controller_move(time: Res<Time>, mut query: FpsControllerQuery) {
         });
 
         match controller.move_mode {
-            MoveMode::Noclip => handle_noclip_mode(input, &mut controller, &mut velocity),
+            MoveMode::Noclip => handle_noclip_mode(&input, &mut controller, &mut velocity),
+            MoveMode::Swim => handle_swim_mode(&input, &controller, &mut velocity, submersion, dt),
+            MoveMode::Climb => handle_climb_mode(
+                &mut commands,
+                entity,
+                &input,
+                &controller,
+                &mut state,
+                &mut velocity,
+                ladder.unwrap_or(Vec3::ZERO),
+            ),
+            MoveMode::Mantle => {
+                handle_mantle_mode(&controller, &mut state, &mut transform, &mut velocity, dt)
+            }
+            MoveMode::WallRun => handle_wall_run_mode(WallRunModeParams {
+                commands: &mut commands,
+                entity,
+                dt,
+                input: &input,
+                controller: &controller,
+                state: &mut state,
+                config: wall_run.unwrap(),
+                velocity: &mut velocity,
+                normal: wall.unwrap(),
+                starting: previous_mode != MoveMode::WallRun,
+            }),
             MoveMode::Ground => {
+                // Move relative to the body we are standing on, e.g. a moving platform
+                let (ground_velocity, ground_spin) =
+                    ground_motion(&controller, shape_hits, &collider_query, &ground_query);
+                velocity.0 -= ground_velocity;
+                input.yaw += ground_spin * dt;
+
                 let params = GroundModeParams {
+                    commands: &mut commands,
                     entity,
+                    spatial_query: &spatial_query,
                     dt,
-                    input,
+                    input: &input,
                     controller: &mut controller,
+                    state: &mut state,
                     collider: &mut collider,
                     transform: &mut transform,
                     velocity: &mut velocity,
                     shape_hits,
                     grounded,
                 };
-                handle_ground_mode(params)
+                handle_ground_mode(params);
+                velocity.0 += ground_velocity;
             }
         }
+        state.jump_held = input.jump;
+    }
+}
+
+/// Finds the velocity of the ground at the point we are standing on it, along with how fast it
+/// spins around the vertical axis. Static ground and ground without a rigid body don't move.
+fn ground_motion(
+    controller: &FpsController,
+    shape_hits: &ShapeHits,
+    collider_query: &Query<&ColliderOf>,
+    ground_query: &GroundBodyQuery,
+) -> (Vec3, f32) {
+    let ground = shape_hits
+        .iter()
+        .filter(|hit| hit.normal1.y > controller.traction_normal_cutoff)
+        .min_by(|a, b| a.distance.total_cmp(&b.distance));
+    let Some(hit) = ground else {
+        return (Vec3::ZERO, 0.0);
+    };
+    let body = collider_query
+        .get(hit.entity)
+        .map_or(hit.entity, |collider_of| collider_of.body);
+    let Ok((linear_velocity, angular_velocity, position, rotation, center_of_mass)) =
+        ground_query.get(body)
+    else {
+        return (Vec3::ZERO, 0.0);
+    };
+    let center = position.0 + rotation.0 * center_of_mass.0;
+    let point_velocity = linear_velocity.0 + angular_velocity.0.cross(hit.point1 - center);
+    (point_velocity, angular_velocity.y)
+}
+
+/// How much of the controller is below the surface of the water it overlaps, from 0 to 1
+fn submersion(
+    spatial_query: &SpatialQueryPipeline,
+    volume_query: &VolumeQuery,
+    entity: Entity,
+    controller: &FpsController,
+    collider: &Collider,
+    transform: &Transform,
+) -> f32 {
+    let filter = SpatialQueryFilter::from_mask(WATER_LAYER).with_excluded_entities([entity]);
+    let surface = spatial_query
+        .shape_intersections(collider, transform.translation, transform.rotation, &filter)
+        .into_iter()
+        .filter_map(|water| volume_query.get(water).ok())
+        .map(|aabb| aabb.max.y)
+        .reduce(f32::max);
+    let Some(surface) = surface else {
+        return 0.0;
+    };
+    let full_height = controller.height + controller.radius * 2.0;
+    let feet = transform.translation.y - full_height / 2.0;
+    ((surface - feet) / full_height).clamp(0.0, 1.0)
+}
+
+fn handle_swim_mode(
+    input: &FpsControllerInput,
+    controller: &FpsController,
+    velocity: &mut LinearVelocity,
+    submersion: f32,
+    dt: f32,
+) {
+    // Swim where we are looking, Jump and Crouch swim straight up and down
+    let mut move_to_world = Mat3::from_euler(EulerRot::YXZ, input.yaw, input.pitch, 0.0);
+    move_to_world.z_axis *= -1.0; // Forward is -Z
+    let vertical = match (input.jump, input.crouch) {
+        (true, false) => 1.0,
+        (false, true) => -1.0,
+        _ => 0.0,
+    };
+    let wish =
+        move_to_world * Vec3::new(input.movement.x, 0.0, input.movement.z) + Vec3::Y * vertical;
+    let wish_speed = f32::min(wish.length(), 1.0) * controller.swim_speed;
+    velocity.0 += acceleration(
+        wish.normalize_or_zero(),
+        wish_speed,
+        controller.acceleration,
+        velocity.0,
+        dt,
+    );
+
+    velocity.y += (controller.swim_buoyancy * submersion - controller.swim_gravity) * dt;
+    velocity.0 /= 1.0 + controller.swim_drag * dt;
+
+    // Jump out of the water when at the surface, e.g. to climb onto a ledge
+    if input.jump && submersion < SURFACE_SUBMERSION {
+        velocity.y = f32::max(velocity.y, controller.jump_speed);
+    }
+}
+
+/// Horizontal direction from the ladder we are touching towards us
+fn ladder_direction(
+    spatial_query: &SpatialQueryPipeline,
+    volume_query: &VolumeQuery,
+    entity: Entity,
+    collider: &Collider,
+    transform: &Transform,
+) -> Option<Vec3> {
+    let filter = SpatialQueryFilter::from_mask(LADDER_LAYER).with_excluded_entities([entity]);
+    let aabb = spatial_query
+        .shape_intersections(collider, transform.translation, transform.rotation, &filter)
+        .into_iter()
+        .find_map(|ladder| volume_query.get(ladder).ok())?;
+    let away = transform.translation - aabb.center();
+    Some(Vec3::new(away.x, 0.0, away.z).normalize_or_zero())
+}
+
+/// Forward climbs up a ladder and backward climbs down it, the other way around while looking
+/// down the ladder
+fn climb_direction(input: &FpsControllerInput) -> f32 {
+    if input.pitch < CLIMB_DOWN_PITCH {
+        -input.movement.z
+    } else {
+        input.movement.z
+    }
+}
+
+fn handle_climb_mode(
+    commands: &mut Commands,
+    entity: Entity,
+    input: &FpsControllerInput,
+    controller: &FpsController,
+    state: &mut FpsControllerState,
+    velocity: &mut LinearVelocity,
+    away: Vec3,
+) {
+    if input.jump {
+        *velocity = LinearVelocity(
+            away * controller.ladder_jump_speed + Vec3::Y * controller.jump_speed * 0.5,
+        );
+        state.ladder_cooldown = LADDER_REGRAB_TIME;
+        commands.send_event(Jumped { entity });
+        return;
+    }
+
+    let right = Mat3::from_axis_angle(Vec3::Y, input.yaw) * Vec3::X;
+    // Keep pressing against the ladder, which also carries us over the top of it
+    *velocity = LinearVelocity(
+        Vec3::Y * climb_direction(input) * controller.climb_speed
+            + right * input.movement.x * controller.climb_speed
+            - away * LADDER_STICK_SPEED,
+    );
+}
+
+/// Normal of a wall to either side of us that we are moving along fast enough to run on
+fn wall_normal(
+    spatial_query: &SpatialQueryPipeline,
+    entity: Entity,
+    input: &FpsControllerInput,
+    controller: &FpsController,
+    config: &WallRunConfig,
+    transform: &Transform,
+    velocity: &LinearVelocity,
+) -> Option<Vec3> {
+    let right = Dir3::new_unchecked(Mat3::from_axis_angle(Vec3::Y, input.yaw) * Vec3::X);
+    let filter = controller_filter(entity);
+    let hit = [right, -right]
+        .into_iter()
+        .filter_map(|direction| {
+            spatial_query.cast_ray(
+                transform.translation,
+                direction,
+                controller.radius + config.reach,
+                true,
+                &filter,
+            )
+        })
+        .filter(|hit| hit.normal.y.abs() < config.wall_normal_cutoff)
+        .min_by(|a, b| a.distance.total_cmp(&b.distance))?;
+
+    let normal = Vec3::new(hit.normal.x, 0.0, hit.normal.z).normalize_or_zero();
+    let lateral = Vec3::new(velocity.x, 0.0, velocity.z);
+    let away = Vec3::dot(lateral, normal);
+    let along = (lateral - away * normal).length();
+    (along >= config.min_speed && away < LEAVE_GROUND_SPEED).then_some(normal)
+}
+
+fn handle_wall_run_mode(params: WallRunModeParams) {
+    let WallRunModeParams {
+        commands,
+        entity,
+        dt,
+        input,
+        controller,
+        state,
+        config,
+        velocity,
+        normal,
+        starting,
+    } = params;
+    state.wall_run_timer = f32::max(state.wall_run_timer - dt, 0.0);
+    // Run along the wall rather than into or away from it
+    velocity.0 -= Vec3::dot(velocity.0, normal) * normal;
+    if starting {
+        // Catch ourselves on the wall instead of carrying on falling
+        velocity.y = f32::max(velocity.y, 0.0);
+    }
+
+    // Jump has to be pressed again once on the wall, holding it from before doesn't count
+    if input.jump && !state.jump_held {
+        velocity.0 += normal * config.jump_speed;
+        velocity.y = controller.jump_speed;
+        commands.send_event(Jumped { entity });
+        return;
     }
+
+    velocity.y -= controller.gravity * config.gravity_scale * dt;
+    velocity.0 -= normal * WALL_STICK_SPEED;
+}
+
+/// Where to stand on a ledge just in front of us that is too high to step onto, if there is one
+/// within reach and room for us on it
+fn mantle_target(
+    spatial_query: &SpatialQueryPipeline,
+    entity: Entity,
+    input: &FpsControllerInput,
+    controller: &FpsController,
+    collider: &Collider,
+    transform: &Transform,
+) -> Option<Vec3> {
+    let forward = Mat3::from_axis_angle(Vec3::Y, input.yaw) * Vec3::NEG_Z;
+    let feet = transform.translation.y - controller.height / 2.0 - controller.radius;
+    let filter = controller_filter(entity);
+
+    // Look down onto the ledge from just past the front of the capsule
+    let mut probe = transform.translation + forward * (controller.radius * 2.0 + GROUND_GAP);
+    probe.y = feet + controller.mantle_height;
+    let hit = spatial_query.cast_ray(
+        probe,
+        Dir3::NEG_Y,
+        controller.mantle_height - controller.step_offset,
+        true,
+        &filter,
+    )?;
+    // Starting inside the wall means the ledge is out of reach
+    if hit.distance <= 0.0 || hit.normal.y <= controller.traction_normal_cutoff {
+        return None;
+    }
+    let ledge = probe.y - hit.distance;
+    let target = Vec3::new(
+        probe.x,
+        ledge + controller.height / 2.0 + controller.radius + GROUND_GAP / 2.0,
+        probe.z,
+    );
+
+    let standing_room = spatial_query
+        .shape_intersections(collider, target, Quat::IDENTITY, &filter)
+        .is_empty();
+    let climbing_room = spatial_query
+        .cast_shape(
+            collider,
+            transform.translation,
+            Quat::IDENTITY,
+            Dir3::Y,
+            &ShapeCastConfig {
+                ignore_origin_penetration: true,
+                ..ShapeCastConfig::from_max_distance(target.y - transform.translation.y)
+            },
+            &filter,
+        )
+        .is_none();
+    (standing_room && climbing_room).then_some(target)
+}
+
+/// Climbs straight up the face of a ledge and then moves onto it
+fn handle_mantle_mode(
+    controller: &FpsController,
+    state: &mut FpsControllerState,
+    transform: &mut Transform,
+    velocity: &mut LinearVelocity,
+    dt: f32,
+) {
+    state.mantle_timer = f32::max(state.mantle_timer - dt, 0.0);
+    let progress = 1.0 - state.mantle_timer / controller.mantle_duration;
+    let rise = f32::min(progress / MANTLE_RISE, 1.0);
+    let over = f32::max((progress - MANTLE_RISE) / (1.0 - MANTLE_RISE), 0.0);
+
+    let (start, target) = (state.mantle_start, state.mantle_target);
+    transform.translation = Vec3::new(
+        start.x.lerp(target.x, over),
+        start.y.lerp(target.y, rise),
+        start.z.lerp(target.z, over),
+    );
+    *velocity = LinearVelocity::ZERO;
 }
 
 fn handle_noclip_mode(
@@ -130,12 +654,15 @@ fn handle_noclip_mode(
 
 fn handle_ground_mode(params: GroundModeParams) {
     let GroundModeParams {
-        entity: _entity,
+        commands,
+        entity,
+        spatial_query,
         dt,
         input,
         controller,
+        state,
         collider,
-        transform: _transform,
+        transform,
         velocity,
         shape_hits,
         grounded,
@@ -157,7 +684,48 @@ fn handle_ground_mode(params: GroundModeParams) {
         } else {
             controller.walk_speed
         };
-        wish_speed = f32::min(wish_speed, max_speed);
+        // A stick pushed part of the way moves us at part of the speed
+        let deflection = f32::min(input.movement.xz().length(), 1.0);
+        wish_speed = f32::min(wish_speed, max_speed * deflection);
+
+        // Remember a jump press for a moment, so that pressing jump just before landing still counts
+        if input.jump {
+            state.jump_buffer = controller.jump_buffer_time;
+        }
+        let wants_jump = input.jump || state.jump_buffer > 0.0;
+        state.jump_buffer = f32::max(state.jump_buffer - dt, 0.0);
+        state.coyote_timer = f32::max(state.coyote_timer - dt, 0.0);
+        let mut jumped = false;
+
+        let sliding = update_slide(
+            &mut commands.entity(entity),
+            input,
+            controller,
+            state,
+            velocity,
+            grounded,
+            dt,
+        );
+        if sliding {
+            // A slide carries our momentum, we can't speed up or steer it
+            wish_speed = 0.0;
+        }
+
+        let lower_sphere = transform.translation - Vec3::Y * controller.height / 2.0;
+        let radius = controller.radius;
+        let gap = |hit: &ShapeHitData| ground_gap(lower_sphere, radius, hit);
+        let contact = |hit: &ShapeHitData, velocity: Vec3| {
+            let leaving_ground = Vec3::dot(velocity, hit.normal1) > LEAVE_GROUND_SPEED;
+            // The ground cast reaches below our feet, only stand on ground we are touching
+            let touching = gap(hit) < GROUND_GAP;
+            touching && !leaving_ground
+        };
+        // Slopes too steep to stand on are surfed, sliding along them without friction
+        let surfing = shape_hits.iter().any(|hit| {
+            hit.normal1.y > f32::EPSILON
+                && hit.normal1.y <= controller.traction_normal_cutoff
+                && contact(hit, velocity.0)
+        });
 
         if !grounded {
             controller.ground_tick = 0;
@@ -174,8 +742,9 @@ fn handle_ground_mode(params: GroundModeParams) {
             add.y = -controller.gravity * dt;
             velocity.0 += add;
 
+            // Speed gained sliding down a ramp is kept
             let air_speed = velocity.0.xz().length();
-            if air_speed > controller.max_air_speed {
+            if !surfing && air_speed > controller.max_air_speed {
                 let ratio = controller.max_air_speed / air_speed;
                 velocity.0.x *= ratio;
                 velocity.0.z *= ratio;
@@ -184,22 +753,43 @@ fn handle_ground_mode(params: GroundModeParams) {
         }
 
         for shape_hit_data in shape_hits.as_slice().iter() {
-            println!("Hit: {:?}", shape_hit_data);
-            let has_traction =
-                Vec3::dot(shape_hit_data.normal1, Vec3::Y) > controller.traction_normal_cutoff;
+            // println!("Hit: {:?}", shape_hit_data);
+            let in_contact = contact(shape_hit_data, velocity.0);
+            let has_traction = Vec3::dot(shape_hit_data.normal1, Vec3::Y)
+                > controller.traction_normal_cutoff
+                && in_contact;
+
+            if surfing && !has_traction {
+                // Air acceleration and gravity were applied above, all the surface does is keep
+                // us from going into it. Close whatever gap is left so that we slide on it
+                // rather than just above it.
+                if in_contact {
+                    let normal = shape_hit_data.normal1;
+                    let closing = normal * f32::max(gap(shape_hit_data), 0.0) / dt;
+                    velocity.0 = clip_velocity(velocity.0 + closing, normal) - closing;
+                }
+                controller.ground_tick = controller.ground_tick.saturating_add(1);
+                continue;
+            }
 
             if controller.ground_tick >= 1 && has_traction {
                 let lateral_speed = velocity.0.xz().length();
                 if lateral_speed > controller.friction_speed_cutoff {
                     let control = f32::max(lateral_speed, controller.stop_speed);
-                    let drop = control * controller.friction * dt;
+                    let friction = if sliding {
+                        controller.slide_friction
+                    } else {
+                        controller.friction
+                    };
+                    let drop = control * friction * dt;
                     let new_speed = f32::max((lateral_speed - drop) / lateral_speed, 0.0);
                     velocity.x *= new_speed;
                     velocity.z *= new_speed;
                 } else {
                     *velocity = LinearVelocity::ZERO;
                 }
-                if controller.ground_tick == 1 {
+                // A slide already follows the ground, snapping to it would lose its speed downhill
+                if controller.ground_tick == 1 && !sliding {
                     velocity.y = -shape_hit_data.distance;
                 }
                 // println!("Ground velocity: {:?}", velocity.0);
@@ -223,15 +813,33 @@ fn handle_ground_mode(params: GroundModeParams) {
                 *velocity = LinearVelocity(
                     linvel - Vec3::dot(linvel, shape_hit_data.normal1) * shape_hit_data.normal1,
                 );
+                if sliding {
+                    // Sliding down a slope speeds us up
+                    let downhill = Vec3::NEG_Y.reject_from_normalized(shape_hit_data.normal1);
+                    velocity.0 += downhill * controller.gravity * dt;
+                }
 
-                if input.jump {
+                state.coyote_timer = controller.coyote_time;
+                if wants_jump {
                     velocity.0.y = controller.jump_speed;
+                    jumped = true;
                 }
             }
 
             controller.ground_tick = controller.ground_tick.saturating_add(1);
         }
 
+        // Coyote time, we can still jump for a moment after walking off a ledge
+        if wants_jump && !jumped && state.coyote_timer > 0.0 {
+            velocity.0.y = controller.jump_speed;
+            jumped = true;
+        }
+        if jumped {
+            state.jump_buffer = 0.0;
+            state.coyote_timer = 0.0;
+            commands.send_event(Jumped { entity });
+        }
+
         let crouch_height = controller.crouch_height;
         let upright_height = controller.upright_height;
 
@@ -240,60 +848,1661 @@ fn handle_ground_mode(params: GroundModeParams) {
         } else {
             controller.uncrouch_speed
         };
-        controller.height += dt * crouch_speed;
-        controller.height = controller.height.clamp(crouch_height, upright_height);
+        let filter = controller_filter(entity);
+        let mut height =
+            (controller.height + dt * crouch_speed).clamp(crouch_height, upright_height);
+
+        // Only stand up as far as the space above us allows
+        let mut crouch_blocked = false;
+        if height > controller.height {
+            let growth = height - controller.height;
+            // Only the top of the capsule, so that the ground we are standing on doesn't count
+            let head = Collider::sphere(controller.radius * 0.99);
+            let headroom = spatial_query.cast_shape(
+                &head,
+                transform.translation + Vec3::Y * controller.height / 2.0,
+                Quat::IDENTITY,
+                Dir3::Y,
+                &ShapeCastConfig::from_max_distance(growth),
+                &filter,
+            );
+            if let Some(hit) = headroom {
+                height = controller.height + hit.distance;
+                crouch_blocked = true;
+            }
+        }
+        controller.height = height;
+        if crouch_blocked {
+            commands.entity(entity).insert(CrouchBlocked);
+        } else {
+            commands.entity(entity).remove::<CrouchBlocked>();
+        }
+
+        let crouched = input.crouch || crouch_blocked;
+        if crouched != state.crouched {
+            state.crouched = crouched;
+            if crouched {
+                commands.send_event(StartedCrouch { entity });
+            } else {
+                commands.send_event(StoppedCrouch { entity });
+            }
+        }
 
         if let Some(capsule) = collider.into() {
-            capsule.set_shape(Collider::capsule(0.5, controller.height).shape().clone());
+            capsule.set_shape(
+                Collider::capsule(controller.radius, controller.height)
+                    .shape()
+                    .clone(),
+            );
         }
 
         // Step offset
-        // if controller.step_offset > f32::EPSILON && controller.ground_tick >= 1 {
-        //     let cast_offset = velocity.normalize_or_zero() * controller.radius * 1.0625;
-        //     let cast = spatial_query.cast_ray(
-        //         transform.translation + cast_offset + Vec3::Y * controller.step_offset * 1.0625,
-        //         Direction3d::NEG_Y,
-        //         controller.step_offset * 0.9375,
-        //         false,
-        //         filter.clone(),
-        //     );
-        //     if let Some(ray_hit_data) = cast {
-        //         transform.translation.y +=
-        //             controller.step_offset * 1.0625 - ray_hit_data.time_of_impact;
-        //         transform.translation += cast_offset;
-        //     }
-        // }
+        if controller.step_offset > f32::EPSILON && controller.ground_tick >= 1 {
+            let stepped_up = step_up(
+                spatial_query,
+                &filter,
+                controller,
+                collider,
+                transform,
+                velocity,
+                dt,
+            );
+            if !stepped_up {
+                step_down(
+                    spatial_query,
+                    &filter,
+                    input,
+                    controller,
+                    collider,
+                    transform,
+                    velocity,
+                );
+            }
+        }
 
         // println!("Linear velocity: {:?}", velocity.xyz());
         // println!("Ground tick: {}", controller.ground_tick);
 
         // Prevent falling off ledges
-        // if controller.ground_tick >= 1 && input.crouch {
-        // for _ in 0..2 {
-        //     // Find the component of our velocity that is overhanging and subtract it off
-        //     let overhang = overhang_component(
-        //         entity,
-        //         transform.as_ref(),
-        //         physics_context.as_ref(),
-        //         velocity.linvel,
-        //         dt,
-        //     );
-        //     if let Some(overhang) = overhang {
-        //         velocity.linvel -= overhang;
-        //     }
-        // }
-        // // If we are still overhanging consider unsolvable and freeze
-        // if overhang_component(
-        //     entity,
-        //     transform.as_ref(),
-        //     physics_context.as_ref(),
-        //     velocity.xyz(),
-        //     dt,
-        // )
-        //     .is_some()
-        // {
-        //     *velocity = LinearVelocity(Vec3::ZERO);
-        // }
-        // }
+        if controller.ledge_guard && controller.ground_tick >= 1 && input.crouch {
+            if let Some(feet) = feet_position(collider, transform) {
+                guard_ledges(spatial_query, entity, controller, feet, velocity, dt);
+            }
+        }
+    }
+}
+
+/// Starts a slide when crouching while sprinting fast enough along the ground, and ends it once
+/// it runs out. Returns whether we are sliding.
+fn update_slide(
+    commands: &mut EntityCommands,
+    input: &FpsControllerInput,
+    controller: &FpsController,
+    state: &mut FpsControllerState,
+    velocity: &LinearVelocity,
+    grounded: bool,
+    dt: f32,
+) -> bool {
+    let lateral_speed = velocity.0.xz().length();
+    let was_sliding = state.slide_timer > 0.0;
+    state.slide_cooldown_timer = f32::max(state.slide_cooldown_timer - dt, 0.0);
+
+    let starting = !was_sliding
+        && input.crouch
+        && !state.crouched
+        && input.sprint
+        && grounded
+        && lateral_speed >= controller.slide_speed
+        && state.slide_cooldown_timer <= 0.0;
+    if starting {
+        state.slide_timer = controller.slide_duration;
+    } else if was_sliding {
+        state.slide_timer = f32::max(state.slide_timer - dt, 0.0);
+        // Back to a normal crouch, or standing up, or a slide jump
+        if !input.crouch || !grounded || lateral_speed <= controller.crouched_speed {
+            state.slide_timer = 0.0;
+        }
+        if state.slide_timer <= 0.0 {
+            state.slide_cooldown_timer = controller.slide_cooldown;
+        }
+    }
+
+    let sliding = state.slide_timer > 0.0;
+    if sliding && !was_sliding {
+        commands.insert(Sliding);
+    } else if !sliding && was_sliding {
+        commands.remove::<Sliding>();
+    }
+    sliding
+}
+
+/// Removes the part of the velocity that would walk the controller off a ledge, so that it
+/// slides along the edge instead.
+fn guard_ledges(
+    spatial_query: &SpatialQueryPipeline,
+    entity: Entity,
+    controller: &FpsController,
+    feet: Vec3,
+    velocity: &mut LinearVelocity,
+    dt: f32,
+) {
+    let overhang = |velocity: Vec3| {
+        overhang_component(
+            entity,
+            spatial_query,
+            feet,
+            velocity,
+            controller.step_offset,
+            controller.radius,
+            dt,
+        )
+    };
+
+    let initial_velocity = velocity.0;
+    let mut edge_normals = Vec::with_capacity(2);
+    for _ in 0..2 {
+        // Find the component of our velocity that is overhanging and subtract it off
+        if let Some(overhang) = overhang(velocity.0) {
+            edge_normals.push(overhang.normalize_or_zero());
+            velocity.0 -= overhang;
+        }
+    }
+    if overhang(velocity.0).is_none() {
+        return;
+    }
+
+    // Still overhanging, most likely at an outside corner. Follow one of the edges we found,
+    // only stopping if neither of them keeps us on the ground
+    let slide = edge_normals
+        .iter()
+        .map(|normal| {
+            let along_edge = normal.cross(Vec3::Y);
+            along_edge * Vec3::dot(initial_velocity, along_edge)
+        })
+        .find(|slide| overhang(*slide).is_none())
+        .unwrap_or(Vec3::ZERO);
+    velocity.x = slide.x;
+    velocity.z = slide.z;
+}
+
+/// Bottom of the controller's capsule in world space
+fn feet_position(collider: &Collider, transform: &Transform) -> Option<Vec3> {
+    let capsule = collider.shape_scaled().as_capsule()?;
+    Some(transform.translation - Vec3::Y * (capsule.half_height() + capsule.radius))
+}
+
+/// Lifts the controller onto a ledge in front of it that is at most `step_offset` high.
+///
+/// The collider is moved up by the step offset, checked for clearance in the direction of
+/// travel and then cast back down to find the top of the step. Returns `true` if the
+/// controller was moved.
+fn step_up(
+    spatial_query: &SpatialQueryPipeline,
+    filter: &SpatialQueryFilter,
+    controller: &FpsController,
+    collider: &Collider,
+    transform: &mut Transform,
+    velocity: &mut LinearVelocity,
+    dt: f32,
+) -> bool {
+    let lateral_velocity = Vec3::new(velocity.x, 0.0, velocity.z);
+    let Ok(direction) = Dir3::new(lateral_velocity) else {
+        return false;
+    };
+    let Some(feet) = feet_position(collider, transform) else {
+        return false;
+    };
+    // Always look a little ahead, otherwise being pressed against a step leaves us stuck
+    let probe_distance = f32::max(lateral_velocity.length() * dt, controller.radius * 0.125);
+
+    let lifted = transform.translation + Vec3::Y * controller.step_offset;
+    let forward_cast = spatial_query.cast_shape(
+        collider,
+        lifted,
+        transform.rotation,
+        direction,
+        &ShapeCastConfig::from_max_distance(probe_distance),
+        filter,
+    );
+    if forward_cast.is_some() {
+        // Either the obstacle is taller than the step offset or there is no room above us
+        return false;
+    }
+
+    let ahead = lifted + direction * probe_distance;
+    let down_cast = spatial_query.cast_shape(
+        collider,
+        ahead,
+        transform.rotation,
+        Dir3::NEG_Y,
+        &ShapeCastConfig::from_max_distance(controller.step_offset),
+        filter,
+    );
+    let Some(hit) = down_cast else {
+        return false;
+    };
+    // The rounded bottom of the capsule can rest on an edge that is higher than the distance
+    // we lifted it, so measure the ledge itself against our feet
+    let ledge_height = hit.point1.y - feet.y;
+    // Flat ground comes back with (almost) the full step offset, only react to actual steps
+    let lift = controller.step_offset - hit.distance;
+    if hit.distance <= f32::EPSILON
+        || lift < controller.step_offset * 0.0625
+        || ledge_height > controller.step_offset + STEP_TOLERANCE
+    {
+        return false;
+    }
+
+    transform.translation = ahead - Vec3::Y * hit.distance;
+    velocity.y = f32::max(velocity.y, 0.0);
+    true
+}
+
+/// Keeps the controller glued to the ground when walking down a drop of at most `step_offset`,
+/// instead of briefly going airborne.
+fn step_down(
+    spatial_query: &SpatialQueryPipeline,
+    filter: &SpatialQueryFilter,
+    input: &FpsControllerInput,
+    controller: &FpsController,
+    collider: &Collider,
+    transform: &mut Transform,
+    velocity: &mut LinearVelocity,
+) {
+    // Don't pull ourselves back down while jumping
+    if input.jump || velocity.y > LEAVE_GROUND_SPEED {
+        return;
+    }
+    let down_cast = spatial_query.cast_shape(
+        collider,
+        transform.translation,
+        transform.rotation,
+        Dir3::NEG_Y,
+        &ShapeCastConfig::from_max_distance(controller.step_offset),
+        filter,
+    );
+    let Some(hit) = down_cast else {
+        return;
+    };
+    if hit.distance <= f32::EPSILON {
+        return;
+    }
+    // When coming off a step the rounded bottom of the capsule catches on its edge first,
+    // only settle onto it if there is walkable ground within reach right below our feet
+    let has_traction = hit.normal1.y > controller.traction_normal_cutoff
+        || feet_position(collider, transform)
+            .and_then(|feet| {
+                spatial_query.cast_ray(feet, Dir3::NEG_Y, controller.step_offset, false, filter)
+            })
+            .is_some_and(|ground| ground.normal.y > controller.traction_normal_cutoff);
+    if has_traction {
+        transform.translation.y -= hit.distance;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+    use crate::FpsPlayerBundle;
+    use bevy::time::TimeUpdateStrategy;
+    use leafwing_input_manager::prelude::*;
+    use std::f32::consts::{FRAC_PI_2, FRAC_PI_3, FRAC_PI_4};
+    use std::time::Duration;
+
+    /// Height of the bottom of the player's capsule
+    fn feet_height(app: &App, player: Entity) -> f32 {
+        let world = app.world();
+        let translation = world.get::<Position>(player).unwrap().0;
+        let controller = world.get::<FpsController>(player).unwrap();
+        translation.y - controller.height / 2.0 - controller.radius
+    }
+
+    /// Spawns a floor with a step of the given height starting 3 units in front of the player,
+    /// then walks forward into it
+    fn walk_into_step(step_height: f32) -> (App, Entity) {
+        let mut app = test_app();
+        spawn_floor(&mut app, 20.0);
+        spawn_box(
+            &mut app,
+            Vec3::new(-20.0, FLOOR_TOP, -20.0),
+            Vec3::new(20.0, FLOOR_TOP + step_height, -3.0),
+        );
+        let player = spawn_player(&mut app, Vec3::new(0.0, FLOOR_TOP + 1.6, 0.0));
+        run(&mut app, 1.0);
+
+        app.world_mut()
+            .get_mut::<FpsControllerInput>(player)
+            .unwrap()
+            .movement = Vec3::Z;
+        run(&mut app, 2.0);
+        (app, player)
+    }
+
+    #[test]
+    fn climbs_steps_up_to_step_offset() {
+        for step_height in [0.2, 0.35, 0.5] {
+            let (app, player) = walk_into_step(step_height);
+            let translation = app.world().get::<Position>(player).unwrap().0;
+            assert!(
+                translation.z < -6.0,
+                "stuck at {translation} on a {step_height} step"
+            );
+            assert!(
+                (feet_height(&app, player) - step_height).abs() < 0.05,
+                "feet at {} on a {step_height} step",
+                feet_height(&app, player)
+            );
+        }
+    }
+
+    #[test]
+    fn blocked_by_steps_above_step_offset() {
+        for step_height in [0.65, 0.8] {
+            let (app, player) = walk_into_step(step_height);
+            let translation = app.world().get::<Position>(player).unwrap().0;
+            assert!(translation.z > -3.0, "climbed a {step_height} step");
+        }
+    }
+
+    #[test]
+    fn steps_down_without_leaving_ground() {
+        for step_height in [0.2, 0.5] {
+            let mut app = test_app();
+            spawn_floor(&mut app, 20.0);
+            spawn_box(
+                &mut app,
+                Vec3::new(-20.0, FLOOR_TOP, -3.0),
+                Vec3::new(20.0, FLOOR_TOP + step_height, 20.0),
+            );
+            let player = spawn_player(&mut app, Vec3::new(0.0, FLOOR_TOP + step_height + 1.6, 0.0));
+            run(&mut app, 1.0);
+
+            app.world_mut()
+                .get_mut::<FpsControllerInput>(player)
+                .unwrap()
+                .movement = Vec3::Z;
+            for _ in 0..120 {
+                app.update();
+                let translation = app.world().get::<Position>(player).unwrap().0;
+                let feet = feet_height(&app, player);
+                assert!(feet < step_height + 0.05, "lifted off to {feet}");
+                assert!(
+                    app.world().entity(player).contains::<Grounded>(),
+                    "left the ground at {translation} after a {step_height} step"
+                );
+                // Once the whole capsule is past the edge we should be standing on the lower floor
+                if translation.z < -3.75 {
+                    assert!(
+                        feet - FLOOR_TOP < 0.05,
+                        "floating at {feet} after a {step_height} step"
+                    );
+                }
+            }
+        }
+    }
+
+    /// Spawns a player crouching on a platform that ends 3 units in front of them, high above
+    /// the floor, then moves them in the given direction for a few seconds
+    fn crouch_towards_ledge(movement: Vec3, ledge_guard: bool) -> (App, Entity) {
+        let mut app = test_app();
+        spawn_floor(&mut app, 20.0);
+        spawn_box(
+            &mut app,
+            Vec3::new(-20.0, FLOOR_TOP, -3.0),
+            Vec3::new(20.0, FLOOR_TOP + 2.0, 20.0),
+        );
+        let player = spawn_player(&mut app, Vec3::new(0.0, FLOOR_TOP + 3.6, 0.0));
+        app.world_mut()
+            .get_mut::<FpsController>(player)
+            .unwrap()
+            .ledge_guard = ledge_guard;
+        app.world_mut()
+            .get_mut::<FpsControllerInput>(player)
+            .unwrap()
+            .crouch = true;
+        run(&mut app, 1.0);
+
+        app.world_mut()
+            .get_mut::<FpsControllerInput>(player)
+            .unwrap()
+            .movement = movement;
+        run(&mut app, 3.0);
+        (app, player)
+    }
+
+    #[test]
+    fn ledge_guard_keeps_crouched_player_on_ledge() {
+        let (app, player) = crouch_towards_ledge(Vec3::Z, true);
+        assert!(
+            (feet_height(&app, player) - 2.0).abs() < 0.05,
+            "fell off the ledge"
+        );
+
+        let (app, player) = crouch_towards_ledge(Vec3::Z, false);
+        assert!(
+            feet_height(&app, player) < 1.9,
+            "ledge guard should be disabled"
+        );
+    }
+
+    #[test]
+    fn ledge_guard_slides_along_edge() {
+        let (app, player) = crouch_towards_ledge(Vec3::new(1.0, 0.0, 1.0), true);
+        let translation = app.world().get::<Position>(player).unwrap().0;
+        assert!(
+            (feet_height(&app, player) - 2.0).abs() < 0.05,
+            "fell off the ledge"
+        );
+        assert!(
+            translation.x > 5.0,
+            "stuck at {translation} instead of sliding"
+        );
+    }
+
+    #[test]
+    fn stays_crouched_under_low_ceiling() {
+        let ceiling = FLOOR_TOP + 2.5;
+        let mut app = test_app();
+        spawn_floor(&mut app, 20.0);
+        spawn_box(
+            &mut app,
+            Vec3::new(-20.0, ceiling, -3.0),
+            Vec3::new(20.0, ceiling + 1.0, 20.0),
+        );
+        let player = spawn_player(&mut app, Vec3::new(0.0, FLOOR_TOP + 1.2, 0.0));
+        app.world_mut()
+            .get_mut::<FpsControllerInput>(player)
+            .unwrap()
+            .crouch = true;
+        run(&mut app, 1.0);
+
+        app.world_mut()
+            .get_mut::<FpsControllerInput>(player)
+            .unwrap()
+            .crouch = false;
+        run(&mut app, 1.0);
+        let world = app.world();
+        let controller = world.get::<FpsController>(player).unwrap();
+        let translation = world.get::<Position>(player).unwrap().0;
+        assert!(controller.height < controller.upright_height);
+        assert!(world.entity(player).contains::<CrouchBlocked>());
+        assert!(translation.y + controller.height / 2.0 + controller.radius < ceiling + 0.05);
+        assert!((feet_height(&app, player) - FLOOR_TOP).abs() < 0.05);
+
+        // Walk out from under the ceiling and stand up
+        app.world_mut()
+            .get_mut::<FpsControllerInput>(player)
+            .unwrap()
+            .movement = Vec3::Z;
+        run(&mut app, 2.0);
+        let world = app.world();
+        let controller = world.get::<FpsController>(player).unwrap();
+        assert_eq!(controller.height, controller.upright_height);
+        assert!(!world.entity(player).contains::<CrouchBlocked>());
+    }
+
+    /// Highest values seen at the end of each fixed tick, independent of how often we render
+    #[derive(Resource, Default)]
+    struct Peaks {
+        speed: f32,
+        height: f32,
+    }
+
+    fn record_peaks(mut peaks: ResMut<Peaks>, query: Query<(&Position, &LinearVelocity)>) {
+        for (position, velocity) in &query {
+            peaks.speed = f32::max(peaks.speed, velocity.xz().length());
+            peaks.height = f32::max(peaks.height, position.y);
+        }
+    }
+
+    /// Sprints and then jumps at the given frame rate, returning the top speed and the height
+    /// of the jump. Every input change happens in between fixed ticks at all tested frame rates.
+    fn sprint_and_jump(fps: f64) -> (f32, f32) {
+        let mut app = test_app();
+        app.insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f64(
+            1.0 / fps,
+        )))
+        .init_resource::<Peaks>()
+        .add_systems(FixedLast, record_peaks);
+        let run_at = |app: &mut App, seconds: f64| {
+            for _ in 0..(seconds * fps).round() as usize {
+                app.update();
+            }
+        };
+        spawn_floor(&mut app, 100.0);
+        let player = spawn_player(&mut app, Vec3::new(0.0, FLOOR_TOP + 1.6, 50.0));
+        run_at(&mut app, 0.9);
+        let start_height = app.world().get::<Position>(player).unwrap().y;
+        *app.world_mut().resource_mut::<Peaks>() = Peaks::default();
+
+        let mut input = app
+            .world_mut()
+            .get_mut::<FpsControllerInput>(player)
+            .unwrap();
+        input.movement = Vec3::Z;
+        input.sprint = true;
+        run_at(&mut app, 1.5);
+        let top_speed = app.world().resource::<Peaks>().speed;
+
+        let mut input = app
+            .world_mut()
+            .get_mut::<FpsControllerInput>(player)
+            .unwrap();
+        input.movement = Vec3::ZERO;
+        input.jump = true;
+        run_at(&mut app, 0.2);
+        app.world_mut()
+            .get_mut::<FpsControllerInput>(player)
+            .unwrap()
+            .jump = false;
+        run_at(&mut app, 1.5);
+        let jump_height = app.world().resource::<Peaks>().height - start_height;
+
+        (top_speed, jump_height)
+    }
+
+    #[test]
+    fn movement_is_frame_rate_independent() {
+        let (reference_speed, reference_height) = sprint_and_jump(60.0);
+        assert!(reference_speed > 10.0, "top speed {reference_speed}");
+        assert!(reference_height > 0.5, "jump height {reference_height}");
+
+        for fps in [30.0, 240.0] {
+            let (top_speed, jump_height) = sprint_and_jump(fps);
+            assert!(
+                (top_speed - reference_speed).abs() < 1e-3,
+                "top speed {top_speed} at {fps} FPS, {reference_speed} at 60 FPS"
+            );
+            assert!(
+                (jump_height - reference_height).abs() < 1e-3,
+                "jump height {jump_height} at {fps} FPS, {reference_height} at 60 FPS"
+            );
+        }
+    }
+
+    #[test]
+    fn controllers_follow_their_own_input() {
+        let mut app = test_app();
+        spawn_floor(&mut app, 50.0);
+        let mut spawn_at = |x: f32| {
+            app.world_mut()
+                .spawn(FpsPlayerBundle::new(Vec3::new(x, FLOOR_TOP + 1.6, 0.0)).with_look(0.0, 0.0))
+                .id()
+        };
+        let forward = spawn_at(-10.0);
+        let right = spawn_at(0.0);
+        let bot = spawn_at(10.0);
+
+        let world = app.world_mut();
+        world
+            .get_mut::<ActionState<FpsActions>>(forward)
+            .unwrap()
+            .press(&FpsActions::Forward);
+        world
+            .get_mut::<ActionState<FpsActions>>(right)
+            .unwrap()
+            .press(&FpsActions::Right);
+        // Without an action state the input is left to whoever writes it
+        world
+            .entity_mut(bot)
+            .remove_with_requires::<InputMap<FpsActions>>();
+        world.get_mut::<FpsControllerInput>(bot).unwrap().movement = Vec3::NEG_Z;
+        run(&mut app, 1.0);
+
+        let position = |entity| app.world().get::<Position>(entity).unwrap().0;
+        let forward = position(forward);
+        assert!(
+            forward.z < -3.0 && (forward.x + 10.0).abs() < 0.01,
+            "moved to {forward}"
+        );
+        let right = position(right);
+        assert!(right.x > 3.0 && right.z.abs() < 0.01, "moved to {right}");
+        let bot = position(bot);
+        assert!(bot.z > 3.0 && (bot.x - 10.0).abs() < 0.01, "moved to {bot}");
+    }
+
+    #[test]
+    fn sticks_move_and_look_by_how_far_they_are_pushed() {
+        let mut app = test_app();
+        spawn_floor(&mut app, 50.0);
+        let mut spawn_pushing = |x: f32, stick: Vec2| {
+            let player = app
+                .world_mut()
+                .spawn(
+                    FpsPlayerBundle::new(Vec3::new(x, FLOOR_TOP + 1.6, 0.0))
+                        .with_look(0.0, 0.0)
+                        .with_gamepad(Entity::PLACEHOLDER),
+                )
+                .id();
+            app.world_mut()
+                .get_mut::<ActionState<FpsActions>>(player)
+                .unwrap()
+                .set_axis_pair(&FpsActions::Move, stick);
+            player
+        };
+        let full = spawn_pushing(-10.0, Vec2::Y);
+        let half = spawn_pushing(10.0, Vec2::Y * 0.5);
+        run(&mut app, 1.0);
+
+        let speed = |entity| app.world().get::<LinearVelocity>(entity).unwrap().length();
+        let walk_speed = FpsController::default().walk_speed;
+        assert!((speed(full) - walk_speed).abs() < 0.1, "{}", speed(full));
+        assert!(
+            (speed(half) - walk_speed / 2.0).abs() < 0.1,
+            "{}",
+            speed(half)
+        );
+
+        // Looking around with the stick doesn't go through the mouse sensitivity
+        let world = app.world_mut();
+        world.get_mut::<LookSettings>(full).unwrap().sensitivity = Vec2::ZERO;
+        world
+            .get_mut::<ActionState<FpsActions>>(full)
+            .unwrap()
+            .set_axis_pair(&FpsActions::Look, Vec2::new(0.0, 1.0));
+        app.update();
+        let input = app.world().get::<FpsControllerInput>(full).unwrap();
+        let look = GamepadLook::default();
+        assert!(
+            (input.pitch - look.sensitivity / 60.0).abs() < 1e-4,
+            "pitch {}",
+            input.pitch
+        );
+        assert_eq!(input.yaw, 0.0);
+    }
+
+    /// Presses jump for a single frame, returning the highest the feet get afterwards
+    fn tap_jump(app: &mut App, player: Entity) -> f32 {
+        *app.world_mut().resource_mut::<Peaks>() = Peaks::default();
+        app.world_mut()
+            .get_mut::<FpsControllerInput>(player)
+            .unwrap()
+            .jump = true;
+        app.update();
+        app.world_mut()
+            .get_mut::<FpsControllerInput>(player)
+            .unwrap()
+            .jump = false;
+        run(app, 1.0);
+        let controller = app.world().get::<FpsController>(player).unwrap();
+        app.world().resource::<Peaks>().height - controller.height / 2.0 - controller.radius
+    }
+
+    #[test]
+    fn single_tap_jumps_full_height() {
+        for jump_speed in [FpsController::default().jump_speed, 3.0] {
+            let mut app = test_app();
+            app.init_resource::<Peaks>()
+                .add_systems(FixedLast, record_peaks);
+            spawn_floor(&mut app, 50.0);
+            let player = spawn_player(&mut app, Vec3::new(0.0, FLOOR_TOP + 1.6, 0.0));
+            let mut controller = app.world_mut().get_mut::<FpsController>(player).unwrap();
+            controller.jump_speed = jump_speed;
+            let expected = jump_speed.powi(2) / (2.0 * controller.gravity);
+            run(&mut app, 0.5);
+
+            // Even a jump too slow to get clear of the ground in one tick isn't stopped by it
+            let height = tap_jump(&mut app, player) - FLOOR_TOP;
+            assert!(
+                (height - expected).abs() < expected * 0.15,
+                "jumped {height} instead of {expected} at {jump_speed}"
+            );
+        }
+    }
+
+    fn jump_before_landing(jump_buffer_time: f32) -> f32 {
+        let mut app = test_app();
+        app.init_resource::<Peaks>()
+            .add_systems(FixedLast, record_peaks);
+        spawn_floor(&mut app, 50.0);
+        let player = spawn_player(&mut app, Vec3::new(0.0, FLOOR_TOP + 4.5, 0.0));
+        app.world_mut()
+            .get_mut::<FpsController>(player)
+            .unwrap()
+            .jump_buffer_time = jump_buffer_time;
+        run(&mut app, 0.05);
+        while feet_height(&app, player) > FLOOR_TOP + 1.2 {
+            app.update();
+        }
+        tap_jump(&mut app, player)
+    }
+
+    #[test]
+    fn jump_buffer_catches_early_press() {
+        let peak = jump_before_landing(FpsController::default().jump_buffer_time);
+        assert!(peak > FLOOR_TOP + 1.5, "feet only got to {peak}");
+
+        let peak = jump_before_landing(0.0);
+        assert!(
+            peak < FLOOR_TOP + 1.25,
+            "jumped to {peak} without a jump buffer"
+        );
+    }
+
+    fn jump_after_walking_off_ledge(coyote_time: f32) -> f32 {
+        let ledge_top = FLOOR_TOP + 2.0;
+        let mut app = test_app();
+        app.init_resource::<Peaks>()
+            .add_systems(FixedLast, record_peaks);
+        spawn_floor(&mut app, 50.0);
+        spawn_box(
+            &mut app,
+            Vec3::new(-50.0, FLOOR_TOP, 0.0),
+            Vec3::new(50.0, ledge_top, 50.0),
+        );
+        let player = spawn_player(&mut app, Vec3::new(0.0, ledge_top + 1.6, 5.0));
+        app.world_mut()
+            .get_mut::<FpsController>(player)
+            .unwrap()
+            .coyote_time = coyote_time;
+        run(&mut app, 0.5);
+
+        app.world_mut()
+            .get_mut::<FpsControllerInput>(player)
+            .unwrap()
+            .movement = Vec3::Z;
+        while feet_height(&app, player) > ledge_top - 0.05 {
+            app.update();
+        }
+        tap_jump(&mut app, player) - ledge_top
+    }
+
+    #[test]
+    fn coyote_time_allows_late_jump() {
+        let height = jump_after_walking_off_ledge(FpsController::default().coyote_time);
+        assert!(height > 1.0, "jumped {height} above the ledge");
+
+        let height = jump_after_walking_off_ledge(0.0);
+        assert!(
+            height < 0.0,
+            "jumped {height} above the ledge without coyote time"
+        );
+    }
+
+    #[derive(Resource, Default)]
+    struct EventLog {
+        events: Vec<String>,
+        impact_speed: f32,
+    }
+
+    fn log_events(
+        mut log: ResMut<EventLog>,
+        mut jumped: EventReader<Jumped>,
+        mut landed: EventReader<Landed>,
+        mut left_ground: EventReader<LeftGround>,
+        mut started_crouch: EventReader<StartedCrouch>,
+        mut stopped_crouch: EventReader<StoppedCrouch>,
+        mut mode_changed: EventReader<ModeChanged>,
+    ) {
+        let mut events = Vec::new();
+        events.extend(jumped.read().map(|_| "jumped".to_string()));
+        events.extend(left_ground.read().map(|_| "left ground".to_string()));
+        for event in landed.read() {
+            events.push("landed".to_string());
+            log.impact_speed = event.impact_speed;
+        }
+        events.extend(started_crouch.read().map(|_| "started crouch".to_string()));
+        events.extend(stopped_crouch.read().map(|_| "stopped crouch".to_string()));
+        events.extend(mode_changed.read().map(|event| format!("{:?}", event.mode)));
+        log.events.extend(events);
+    }
+
+    #[test]
+    fn sends_controller_events() {
+        let mut app = test_app();
+        app.init_resource::<EventLog>()
+            .add_systems(Update, log_events);
+        spawn_floor(&mut app, 50.0);
+        let player = spawn_player(&mut app, Vec3::new(0.0, FLOOR_TOP + 4.0, 0.0));
+        let step = |app: &mut App, input: fn(&mut FpsControllerInput), seconds: f32| {
+            input(
+                &mut app
+                    .world_mut()
+                    .get_mut::<FpsControllerInput>(player)
+                    .unwrap(),
+            );
+            run(app, seconds);
+            std::mem::take(&mut app.world_mut().resource_mut::<EventLog>().events)
+        };
+        let impact_speed = |app: &App| app.world().resource::<EventLog>().impact_speed;
+
+        assert_eq!(step(&mut app, |_| {}, 1.0), ["landed"]);
+        assert!(impact_speed(&app) > 5.0, "landed at {}", impact_speed(&app));
+        let mut events = step(&mut app, |input| input.jump = true, 1.0 / 60.0);
+        events.extend(step(&mut app, |input| input.jump = false, 1.5));
+        assert_eq!(events, ["jumped", "left ground", "landed"]);
+        assert!(impact_speed(&app) > 5.0, "landed at {}", impact_speed(&app));
+        assert_eq!(
+            step(&mut app, |input| input.crouch = true, 0.5),
+            ["started crouch"]
+        );
+        assert_eq!(
+            step(&mut app, |input| input.crouch = false, 0.5),
+            ["stopped crouch"]
+        );
+        assert_eq!(
+            step(&mut app, |input| input.fly = true, 1.0 / 60.0),
+            ["Noclip"]
+        );
+    }
+
+    /// Fastest any controller fell at the end of a tick
+    #[derive(Resource, Default)]
+    struct FallSpeed(f32);
+
+    fn record_fall_speed(mut fall_speed: ResMut<FallSpeed>, query: Query<&LinearVelocity>) {
+        for velocity in &query {
+            fall_speed.0 = f32::max(fall_speed.0, -velocity.y);
+        }
+    }
+
+    /// Height of the feet and impact speed of every landing
+    #[derive(Resource, Default)]
+    struct Landings(Vec<(f32, f32)>);
+
+    fn record_landings(
+        mut landed: EventReader<Landed>,
+        query: Query<(&Position, &FpsController)>,
+        mut landings: ResMut<Landings>,
+    ) {
+        for event in landed.read() {
+            let (position, controller) = query.get(event.entity).unwrap();
+            let feet = position.y - controller.height / 2.0 - controller.radius;
+            landings.0.push((feet, event.impact_speed));
+        }
+    }
+
+    #[test]
+    fn lands_when_it_hits_the_ground() {
+        for height in [1.0, 3.0, 6.0] {
+            let (mut app, _) = floor_app(height);
+            app.init_resource::<Landings>()
+                .init_resource::<FallSpeed>()
+                .add_systems(Update, record_landings)
+                .add_systems(FixedLast, record_fall_speed);
+            run(&mut app, 2.0);
+
+            // Not as soon as the ground is in sight, but on the tick we hit it at our fastest
+            let fall_speed = app.world().resource::<FallSpeed>().0;
+            let [(feet, impact_speed)] = app.world().resource::<Landings>().0[..] else {
+                panic!("landed {:?}", app.world().resource::<Landings>().0);
+            };
+            assert!(
+                (feet - FLOOR_TOP).abs() < 0.02,
+                "landed {feet} above the floor from {height}"
+            );
+            assert!(
+                (fall_speed - impact_speed).abs() < 0.01,
+                "landed at {impact_speed} after falling at {fall_speed} from {height}"
+            );
+        }
+    }
+
+    fn spawn_platform(app: &mut App, size: Vec3, velocity: Vec3, spin: f32) -> Entity {
+        app.world_mut()
+            .spawn((
+                Collider::cuboid(size.x, size.y, size.z),
+                RigidBody::Kinematic,
+                LinearVelocity(velocity),
+                AngularVelocity(Vec3::Y * spin),
+                Transform::from_xyz(0.0, FLOOR_TOP - size.y / 2.0, 0.0),
+            ))
+            .id()
+    }
+
+    #[test]
+    fn rides_moving_platform() {
+        let mut app = test_app();
+        // Nothing else to stand on, if the platform leaves us behind we fall
+        let platform = spawn_platform(
+            &mut app,
+            Vec3::new(4.0, 1.0, 4.0),
+            Vec3::new(3.0, 1.0, 0.0),
+            0.0,
+        );
+        let player = spawn_player(&mut app, Vec3::new(0.0, FLOOR_TOP + 1.6, 0.0));
+        let offset = |app: &App| {
+            let world = app.world();
+            world.get::<Position>(player).unwrap().0 - world.get::<Position>(platform).unwrap().0
+        };
+        // Landing on the platform takes a moment to catch up with it
+        run(&mut app, 0.5);
+        let start = offset(&app);
+        run(&mut app, 1.5);
+
+        let end = offset(&app);
+        assert!(
+            (end - start).length() < 0.05,
+            "moved from {start} to {end} relative to the platform"
+        );
+        assert!(
+            (end.y - 2.0).abs() < 0.05,
+            "standing {} above the platform center",
+            end.y
+        );
+    }
+
+    #[test]
+    fn turns_with_rotating_platform() {
+        let spin = 1.0;
+        let mut app = test_app();
+        spawn_platform(&mut app, Vec3::new(8.0, 1.0, 8.0), Vec3::ZERO, spin);
+        let player = spawn_player(&mut app, Vec3::new(2.0, FLOOR_TOP + 1.6, 0.0));
+        run(&mut app, 0.5);
+        let start = app.world().get::<Position>(player).unwrap().0.xz();
+        let start_yaw = app.world().get::<FpsControllerInput>(player).unwrap().yaw;
+        run(&mut app, 1.5);
+
+        let world = app.world();
+        let turned = world.get::<Position>(player).unwrap().0.xz();
+        // Seen from above, spinning around +Y turns from +X towards -Z
+        let angle = Vec2::new(start.x, -start.y).angle_to(Vec2::new(turned.x, -turned.y));
+        assert!(
+            (angle - spin * 1.5).abs() < 0.1,
+            "turned {angle} radians, from {start} to {turned}"
+        );
+        assert!(
+            (turned.length() - start.length()).abs() < 0.25,
+            "drifted from {start} to {turned}"
+        );
+        let yaw = world.get::<FpsControllerInput>(player).unwrap().yaw;
+        assert!(
+            (yaw - start_yaw - spin * 1.5).abs() < 0.1,
+            "looked from {start_yaw} to {yaw}"
+        );
+    }
+
+    fn floor_app(height: f32) -> (App, Entity) {
+        let mut app = test_app();
+        spawn_floor(&mut app, 20.0);
+        let player = spawn_player(&mut app, Vec3::new(0.0, FLOOR_TOP + height + 1.5, 0.0));
+        (app, player)
+    }
+
+    #[test]
+    fn lands_on_ground_instead_of_hovering() {
+        let (mut app, player) = floor_app(3.0);
+        run(&mut app, 1.5);
+        let feet = feet_height(&app, player) - FLOOR_TOP;
+        assert!(feet.abs() < 0.02, "feet {feet} above the floor");
+        assert!(app.world().entity(player).contains::<Grounded>());
+    }
+
+    #[test]
+    fn crouches_and_stands_up_on_ground() {
+        let (mut app, player) = floor_app(0.0);
+        run(&mut app, 0.5);
+        app.world_mut()
+            .get_mut::<FpsControllerInput>(player)
+            .unwrap()
+            .crouch = true;
+        run(&mut app, 1.0);
+        // The floor is neither a ceiling nor too far below to stand on
+        let world = app.world();
+        let controller = world.get::<FpsController>(player).unwrap();
+        assert_eq!(controller.height, controller.crouch_height);
+        assert!(world.entity(player).contains::<Grounded>());
+        assert!(!world.entity(player).contains::<CrouchBlocked>());
+        let feet = feet_height(&app, player) - FLOOR_TOP;
+        assert!(feet.abs() < 0.02, "feet {feet} above the floor");
+
+        app.world_mut()
+            .get_mut::<FpsControllerInput>(player)
+            .unwrap()
+            .crouch = false;
+        // Standing up all the way without the floor ever holding us down
+        let mut height = 0.0;
+        for _ in 0..60 {
+            app.update();
+            let world = app.world();
+            let controller = world.get::<FpsController>(player).unwrap();
+            assert!(
+                controller.height >= height,
+                "shrank to {}",
+                controller.height
+            );
+            assert!(!world.entity(player).contains::<CrouchBlocked>());
+            height = controller.height;
+        }
+        let controller = app.world().get::<FpsController>(player).unwrap();
+        assert_eq!(controller.height, controller.upright_height);
+        let feet = feet_height(&app, player) - FLOOR_TOP;
+        assert!(feet.abs() < 0.02, "feet {feet} above the floor");
+    }
+
+    fn spawn_water(app: &mut App, min: Vec3, max: Vec3) {
+        let size = max - min;
+        app.world_mut().spawn((
+            Collider::cuboid(size.x, size.y, size.z),
+            Sensor,
+            Water,
+            CollisionLayers::new(WATER_LAYER, LayerMask::ALL),
+            Transform::from_translation((min + max) / 2.0),
+        ));
+    }
+
+    /// Spawns a pool of water 4 units deep, with a ledge just above the surface starting 3
+    /// units in front of the player
+    fn swim_app() -> (App, Entity) {
+        let mut app = test_app();
+        spawn_floor(&mut app, 50.0);
+        spawn_water(
+            &mut app,
+            Vec3::new(-10.0, FLOOR_TOP, -3.0),
+            Vec3::new(10.0, FLOOR_TOP + 4.0, 10.0),
+        );
+        spawn_box(
+            &mut app,
+            Vec3::new(-10.0, FLOOR_TOP, -10.0),
+            Vec3::new(10.0, FLOOR_TOP + 4.5, -3.0),
+        );
+        let player = spawn_player(&mut app, Vec3::new(0.0, FLOOR_TOP + 2.0, 0.0));
+        (app, player)
+    }
+
+    #[test]
+    fn floats_in_water() {
+        let (mut app, player) = swim_app();
+        run(&mut app, 4.0);
+
+        let controller = app.world().get::<FpsController>(player).unwrap();
+        assert_eq!(controller.move_mode, MoveMode::Swim);
+        // Buoyancy cancels out gravity once this much of us is under water
+        let full_height = controller.height + controller.radius * 2.0;
+        let expected = controller.swim_gravity / controller.swim_buoyancy;
+        let submerged = (FLOOR_TOP + 4.0 - feet_height(&app, player)) / full_height;
+        assert!(
+            (submerged - expected).abs() < 0.05,
+            "{submerged} under water instead of {expected}"
+        );
+    }
+
+    #[test]
+    fn swims_down_and_jumps_out_of_water() {
+        let (mut app, player) = swim_app();
+        run(&mut app, 1.0);
+        let floating = feet_height(&app, player);
+
+        app.world_mut()
+            .get_mut::<FpsControllerInput>(player)
+            .unwrap()
+            .crouch = true;
+        run(&mut app, 1.0);
+        let dived = feet_height(&app, player);
+        assert!(
+            dived < floating - 1.0,
+            "only dived from {floating} to {dived}"
+        );
+
+        let mut input = app
+            .world_mut()
+            .get_mut::<FpsControllerInput>(player)
+            .unwrap();
+        input.crouch = false;
+        input.jump = true;
+        input.movement = Vec3::Z;
+        run(&mut app, 1.5);
+        let mut input = app
+            .world_mut()
+            .get_mut::<FpsControllerInput>(player)
+            .unwrap();
+        input.jump = false;
+        input.movement = Vec3::ZERO;
+        run(&mut app, 1.0);
+        assert_eq!(
+            app.world().get::<FpsController>(player).unwrap().move_mode,
+            MoveMode::Ground
+        );
+        assert!(
+            (feet_height(&app, player) - (FLOOR_TOP + 4.5)).abs() < 0.05,
+            "feet at {} instead of on the ledge",
+            feet_height(&app, player)
+        );
+    }
+
+    /// Spawns a wall 4 units high starting 2 units in front of the player, with a ladder up its
+    /// face that reaches a little above the top
+    fn ladder_app() -> (App, Entity) {
+        let mut app = test_app();
+        spawn_floor(&mut app, 50.0);
+        spawn_box(
+            &mut app,
+            Vec3::new(-10.0, FLOOR_TOP, -30.0),
+            Vec3::new(10.0, FLOOR_TOP + 4.0, -2.0),
+        );
+        let (min, max) = (
+            Vec3::new(-1.0, FLOOR_TOP, -2.5),
+            Vec3::new(1.0, FLOOR_TOP + 4.25, -1.5),
+        );
+        let size = max - min;
+        app.world_mut().spawn((
+            Collider::cuboid(size.x, size.y, size.z),
+            Sensor,
+            Ladder,
+            CollisionLayers::new(LADDER_LAYER, LayerMask::ALL),
+            Transform::from_translation((min + max) / 2.0),
+        ));
+        let player = spawn_player(&mut app, Vec3::new(0.0, FLOOR_TOP + 1.0, 0.0));
+        run(&mut app, 0.5);
+        (app, player)
+    }
+
+    #[test]
+    fn climbs_ladder_onto_ledge() {
+        let (mut app, player) = ladder_app();
+        app.world_mut()
+            .get_mut::<FpsControllerInput>(player)
+            .unwrap()
+            .movement = Vec3::Z;
+        run(&mut app, 1.0);
+        assert_eq!(
+            app.world().get::<FpsController>(player).unwrap().move_mode,
+            MoveMode::Climb
+        );
+
+        run(&mut app, 2.0);
+        assert_eq!(
+            app.world().get::<FpsController>(player).unwrap().move_mode,
+            MoveMode::Ground
+        );
+        let translation = app.world().get::<Position>(player).unwrap().0;
+        assert!(translation.z < -2.5, "stopped at {translation}");
+        assert!(
+            (feet_height(&app, player) - (FLOOR_TOP + 4.0)).abs() < 0.05,
+            "feet at {} instead of on the ledge",
+            feet_height(&app, player)
+        );
+    }
+
+    #[test]
+    fn climbs_down_and_jumps_off_ladder() {
+        let (mut app, player) = ladder_app();
+        app.world_mut()
+            .get_mut::<FpsControllerInput>(player)
+            .unwrap()
+            .movement = Vec3::Z;
+        run(&mut app, 1.0);
+        let climbed = feet_height(&app, player);
+        assert!(climbed > FLOOR_TOP + 1.0, "only climbed to {climbed}");
+
+        // Looking down the ladder turns forward into down
+        app.world_mut()
+            .get_mut::<FpsControllerInput>(player)
+            .unwrap()
+            .pitch = -FRAC_PI_4;
+        run(&mut app, 0.25);
+        let descended = feet_height(&app, player);
+        assert!(
+            descended < climbed - 0.5,
+            "only climbed down from {climbed} to {descended}"
+        );
+        assert_eq!(
+            app.world().get::<FpsController>(player).unwrap().move_mode,
+            MoveMode::Climb
+        );
+
+        let mut input = app
+            .world_mut()
+            .get_mut::<FpsControllerInput>(player)
+            .unwrap();
+        input.movement = Vec3::ZERO;
+        input.jump = true;
+        app.update();
+        app.world_mut()
+            .get_mut::<FpsControllerInput>(player)
+            .unwrap()
+            .jump = false;
+        run(&mut app, 2.0);
+        let translation = app.world().get::<Position>(player).unwrap().0;
+        assert!(translation.z > -0.5, "still on the ladder at {translation}");
+        assert_eq!(
+            app.world().get::<FpsController>(player).unwrap().move_mode,
+            MoveMode::Ground
+        );
+    }
+
+    /// Spawns a 60 degree ramp running along the X axis and sloping down towards +Z, too steep
+    /// to stand on, and drops the player onto it
+    fn surf_app() -> (App, Entity) {
+        let mut app = test_app();
+        let rotation = Quat::from_rotation_x(FRAC_PI_3);
+        app.world_mut().spawn((
+            Collider::cuboid(400.0, 1.0, 200.0),
+            RigidBody::Static,
+            Transform::from_rotation(rotation),
+        ));
+        let controller = FpsController::default();
+        let surface = rotation * Vec3::Y * 0.5;
+        let player = spawn_player(
+            &mut app,
+            surface + Vec3::Y * (controller.height / 2.0 + controller.radius + 1.5),
+        );
+        (app, player)
+    }
+
+    fn speed(app: &App, player: Entity) -> f32 {
+        app.world().get::<LinearVelocity>(player).unwrap().length()
+    }
+
+    #[test]
+    fn slides_down_steep_ramp() {
+        let (mut app, player) = surf_app();
+        run(&mut app, 1.0);
+        let world = app.world();
+        assert!(world.get::<Grounded>(player).is_none());
+        let velocity = world.get::<LinearVelocity>(player).unwrap().0;
+        // Sliding straight down the slope rather than standing or bouncing on it
+        let down_slope = Vec3::new(0.0, -FRAC_PI_3.sin(), FRAC_PI_3.cos());
+        assert!(
+            velocity.normalize().dot(down_slope) > 0.99,
+            "moving {velocity} instead of down the ramp"
+        );
+        // and against it rather than gliding above it
+        let controller = world.get::<FpsController>(player).unwrap();
+        let lower_sphere =
+            world.get::<Position>(player).unwrap().0 - Vec3::Y * controller.height / 2.0;
+        let normal = Quat::from_rotation_x(FRAC_PI_3) * Vec3::Y;
+        let gap = lower_sphere.dot(normal) - 0.5 - controller.radius;
+        assert!(gap.abs() < 0.05, "{gap} above the ramp");
+    }
+
+    #[test]
+    fn surfs_without_losing_speed() {
+        let (mut app, player) = surf_app();
+        run(&mut app, 0.25);
+        app.world_mut().get_mut::<LinearVelocity>(player).unwrap().x = 12.0;
+
+        let mut previous = speed(&app, player);
+        for _ in 0..90 {
+            app.update();
+            let speed = speed(&app, player);
+            assert!(
+                speed >= previous - 0.01,
+                "slowed from {previous} to {speed}"
+            );
+            previous = speed;
+        }
+        let velocity = app.world().get::<LinearVelocity>(player).unwrap().0;
+        // Nothing acts along the ramp, so its speed along it is kept exactly
+        assert!(
+            (velocity.x - 12.0).abs() < 0.01,
+            "speed along the ramp went from 12 to {}",
+            velocity.x
+        );
+        // and falling down it carries us past the usual air speed limit
+        let max_air_speed = app
+            .world()
+            .get::<FpsController>(player)
+            .unwrap()
+            .max_air_speed;
+        assert!(velocity.xz().length() > max_air_speed);
+    }
+
+    /// Sprints forward over ground that slopes down by `slope` radians for long enough to reach
+    /// full speed
+    fn sprint_app(slope: f32) -> (App, Entity) {
+        let mut app = test_app();
+        app.world_mut().spawn((
+            Collider::cuboid(400.0, 1.0, 400.0),
+            RigidBody::Static,
+            Transform::from_xyz(0.0, FLOOR_TOP - 0.5, 0.0)
+                .with_rotation(Quat::from_rotation_x(-slope)),
+        ));
+        let player = spawn_player(&mut app, Vec3::new(0.0, FLOOR_TOP + 1.6, 0.0));
+        run(&mut app, 0.5);
+
+        let mut input = app
+            .world_mut()
+            .get_mut::<FpsControllerInput>(player)
+            .unwrap();
+        input.movement = Vec3::Z;
+        input.sprint = true;
+        run(&mut app, 1.5);
+        (app, player)
+    }
+
+    fn press_crouch(app: &mut App, player: Entity) {
+        app.world_mut()
+            .get_mut::<FpsControllerInput>(player)
+            .unwrap()
+            .crouch = true;
+    }
+
+    fn lateral_speed(app: &App, player: Entity) -> f32 {
+        app.world()
+            .get::<LinearVelocity>(player)
+            .unwrap()
+            .xz()
+            .length()
+    }
+
+    #[test]
+    fn slide_keeps_momentum_then_crouches() {
+        let (mut app, player) = sprint_app(0.0);
+        let sprinting = lateral_speed(&app, player);
+        press_crouch(&mut app, player);
+        run(&mut app, 0.5);
+        assert!(app.world().entity(player).contains::<Sliding>());
+        let sliding = lateral_speed(&app, player);
+        assert!(
+            sliding > sprinting * 0.8,
+            "slowed from {sprinting} to {sliding}"
+        );
+        assert!(sliding > app.world().get::<FpsController>(player).unwrap().run_speed * 0.5);
+
+        run(&mut app, 1.5);
+        let world = app.world();
+        let controller = world.get::<FpsController>(player).unwrap();
+        assert!(!world.entity(player).contains::<Sliding>());
+        assert!(world.get::<FpsControllerState>(player).unwrap().crouched);
+        assert!(lateral_speed(&app, player) <= controller.crouched_speed + 0.1);
+    }
+
+    #[test]
+    fn only_slides_when_sprinting_fast_enough() {
+        let (mut app, player) = sprint_app(0.0);
+        app.world_mut()
+            .get_mut::<FpsControllerInput>(player)
+            .unwrap()
+            .sprint = false;
+        run(&mut app, 1.0);
+        press_crouch(&mut app, player);
+        run(&mut app, 0.1);
+        assert!(!app.world().entity(player).contains::<Sliding>());
+    }
+
+    #[test]
+    fn slides_faster_downhill() {
+        // Speed lost over half a second of sliding
+        let slow_down = |slope: f32| {
+            let (mut app, player) = sprint_app(slope);
+            let speed = |app: &App| app.world().get::<LinearVelocity>(player).unwrap().length();
+            let start = speed(&app);
+            press_crouch(&mut app, player);
+            run(&mut app, 0.5);
+            assert!(app.world().entity(player).contains::<Sliding>());
+            start - speed(&app)
+        };
+        let flat = slow_down(0.0);
+        let downhill = slow_down(0.2);
+        assert!(flat > 0.5, "lost {flat} speed sliding on the flat");
+        // Going downhill a slide keeps its speed, or even speeds up
+        assert!(
+            downhill <= 0.0,
+            "lost {downhill} speed sliding downhill and {flat} on the flat"
+        );
+    }
+
+    #[test]
+    fn slide_has_cooldown() {
+        let (mut app, player) = sprint_app(0.0);
+        press_crouch(&mut app, player);
+        run(&mut app, 0.1);
+        assert!(app.world().entity(player).contains::<Sliding>());
+
+        // Standing back up ends the slide, we sprint on but can't slide again straight away
+        app.world_mut()
+            .get_mut::<FpsControllerInput>(player)
+            .unwrap()
+            .crouch = false;
+        run(&mut app, 0.1);
+        assert!(!app.world().entity(player).contains::<Sliding>());
+        press_crouch(&mut app, player);
+        run(&mut app, 0.1);
+        assert!(!app.world().entity(player).contains::<Sliding>());
+    }
+
+    /// Sprints along a tall wall just to the right of the player and jumps, with wall-running
+    /// enabled or not
+    fn wall_run_app(wall_run: bool) -> (App, Entity) {
+        let mut app = test_app();
+        spawn_box(
+            &mut app,
+            Vec3::new(-50.0, FLOOR_TOP - 1.0, -100.0),
+            Vec3::new(50.0, FLOOR_TOP, 50.0),
+        );
+        spawn_box(
+            &mut app,
+            Vec3::new(0.55, FLOOR_TOP, -100.0),
+            Vec3::new(1.5, FLOOR_TOP + 20.0, 20.0),
+        );
+        let player = spawn_player(&mut app, Vec3::new(0.0, FLOOR_TOP + 1.6, 0.0));
+        if wall_run {
+            app.world_mut()
+                .entity_mut(player)
+                .insert(WallRunConfig::default());
+        }
+        run(&mut app, 0.5);
+
+        let mut input = app
+            .world_mut()
+            .get_mut::<FpsControllerInput>(player)
+            .unwrap();
+        input.movement = Vec3::Z;
+        input.sprint = true;
+        run(&mut app, 1.0);
+        press_jump(&mut app, player);
+        (app, player)
+    }
+
+    fn press_jump(app: &mut App, player: Entity) {
+        app.world_mut()
+            .get_mut::<FpsControllerInput>(player)
+            .unwrap()
+            .jump = true;
+        run(app, 0.05);
+        app.world_mut()
+            .get_mut::<FpsControllerInput>(player)
+            .unwrap()
+            .jump = false;
+    }
+
+    fn move_mode(app: &App, player: Entity) -> MoveMode {
+        app.world().get::<FpsController>(player).unwrap().move_mode
+    }
+
+    #[test]
+    fn runs_along_wall_until_it_runs_out() {
+        let (mut app, player) = wall_run_app(true);
+        run(&mut app, 1.0);
+        assert_eq!(move_mode(&app, player), MoveMode::WallRun);
+        assert!(
+            feet_height(&app, player) > FLOOR_TOP + 2.0,
+            "fell to {} on the wall",
+            feet_height(&app, player)
+        );
+        assert!(lateral_speed(&app, player) > 5.0);
+
+        run(&mut app, 1.5);
+        assert_eq!(move_mode(&app, player), MoveMode::Ground);
+        assert!(feet_height(&app, player) - FLOOR_TOP < 0.05);
+    }
+
+    #[test]
+    fn only_wall_runs_with_config() {
+        let (mut app, player) = wall_run_app(false);
+        run(&mut app, 1.0);
+        assert_eq!(move_mode(&app, player), MoveMode::Ground);
+        assert!(feet_height(&app, player) - FLOOR_TOP < 0.05);
+    }
+
+    #[test]
+    fn wall_jump_pushes_off_wall() {
+        let (mut app, player) = wall_run_app(true);
+        run(&mut app, 0.3);
+        assert_eq!(move_mode(&app, player), MoveMode::WallRun);
+        let on_wall = app.world().get::<Position>(player).unwrap().0;
+
+        press_jump(&mut app, player);
+        run(&mut app, 0.25);
+        let jumped = app.world().get::<Position>(player).unwrap().0;
+        assert_eq!(move_mode(&app, player), MoveMode::Ground);
+        assert!(
+            jumped.x < on_wall.x - 1.0,
+            "only moved from {on_wall} to {jumped}"
+        );
+        assert!(jumped.y > on_wall.y, "fell from {on_wall} to {jumped}");
+    }
+
+    /// Walks into a wall with a ledge at the given height, jumping once against it
+    fn mantle_app(ledge_height: f32, forward: bool) -> (App, Entity) {
+        let mut app = test_app();
+        spawn_floor(&mut app, 50.0);
+        spawn_box(
+            &mut app,
+            Vec3::new(-10.0, FLOOR_TOP, -20.0),
+            Vec3::new(10.0, FLOOR_TOP + ledge_height, -2.0),
+        );
+        let player = spawn_player(&mut app, Vec3::new(0.0, FLOOR_TOP + 1.6, 0.0));
+        run(&mut app, 0.5);
+
+        app.world_mut()
+            .get_mut::<FpsControllerInput>(player)
+            .unwrap()
+            .movement = Vec3::Z;
+        run(&mut app, 0.5);
+        if !forward {
+            app.world_mut()
+                .get_mut::<FpsControllerInput>(player)
+                .unwrap()
+                .movement = Vec3::ZERO;
+        }
+        press_jump(&mut app, player);
+        (app, player)
+    }
+
+    #[test]
+    fn mantles_ledge_out_of_jumping_reach() {
+        let (mut app, player) = mantle_app(2.0, true);
+        let mut mantled = false;
+        for _ in 0..90 {
+            app.update();
+            mantled |= move_mode(&app, player) == MoveMode::Mantle;
+        }
+        assert!(mantled);
+        assert_eq!(move_mode(&app, player), MoveMode::Ground);
+        let translation = app.world().get::<Position>(player).unwrap().0;
+        assert!(translation.z < -2.5, "stopped at {translation}");
+        assert!(
+            (feet_height(&app, player) - (FLOOR_TOP + 2.0)).abs() < 0.05,
+            "feet at {} instead of on the ledge",
+            feet_height(&app, player)
+        );
+    }
+
+    #[test]
+    fn does_not_mantle_high_ledges_or_without_moving_forward() {
+        for (ledge_height, forward) in [(4.0, true), (2.0, false)] {
+            let (mut app, player) = mantle_app(ledge_height, forward);
+            for _ in 0..90 {
+                app.update();
+                assert_ne!(move_mode(&app, player), MoveMode::Mantle);
+            }
+            assert!(feet_height(&app, player) - FLOOR_TOP < 0.05);
+        }
+    }
+
+    #[derive(Resource, Default)]
+    struct Deaths(usize);
+
+    /// Drops a player with fall damage onto the floor from `height`, returning its health after
+    /// landing and whether it died
+    fn drop_player(height: f32) -> (Health, bool) {
+        let mut app = test_app();
+        app.init_resource::<Deaths>().add_systems(
+            Update,
+            |mut died: EventReader<Died>, mut deaths: ResMut<Deaths>| {
+                deaths.0 += died.read().count();
+            },
+        );
+        spawn_floor(&mut app, 50.0);
+        let player = spawn_player(&mut app, Vec3::new(0.0, FLOOR_TOP + height + 1.25, 0.0));
+        app.world_mut()
+            .entity_mut(player)
+            .insert((Health::new(100.0), FallDamage::default()))
+            // Stay where we died rather than respawning for another fall
+            .remove::<RespawnPoint>();
+        run(&mut app, 3.0);
+
+        assert!(feet_height(&app, player) - FLOOR_TOP < 0.05);
+        let deaths = app.world().resource::<Deaths>().0;
+        assert!(deaths <= 1, "died {deaths} times");
+        (*app.world().get::<Health>(player).unwrap(), deaths == 1)
+    }
+
+    #[test]
+    fn fall_damage_scales_with_height() {
+        let (short, died) = drop_player(2.0);
+        assert_eq!(short.current, 100.0);
+        assert!(!died);
+
+        let (medium, died) = drop_player(6.0);
+        let (high, _) = drop_player(10.0);
+        assert!(!died);
+        assert!(
+            0.0 < high.current && high.current < medium.current && medium.current < 100.0,
+            "health {} after a medium fall and {} after a high one",
+            medium.current,
+            high.current
+        );
+    }
+
+    #[test]
+    fn lethal_fall_kills() {
+        let (health, died) = drop_player(25.0);
+        assert!(health.is_dead());
+        assert!(died);
+    }
+
+    #[test]
+    fn fall_damage_severity() {
+        let fall_damage = FallDamage::default();
+        assert_eq!(fall_damage.severity(12.0), 0.0);
+        assert_eq!(fall_damage.severity(21.0), 0.5);
+        assert_eq!(fall_damage.severity(30.0), 1.0);
+        assert_eq!(fall_damage.severity(100.0), 1.0);
+        // Without any speed in between, landing is either harmless or lethal
+        let cliff = FallDamage {
+            safe_speed: 20.0,
+            lethal_speed: 20.0,
+        };
+        assert_eq!(cliff.severity(19.0), 0.0);
+        assert_eq!(cliff.severity(20.0), 1.0);
+        assert_eq!(cliff.severity(f32::INFINITY), 1.0);
+        let backwards = FallDamage {
+            safe_speed: 20.0,
+            lethal_speed: 10.0,
+        };
+        assert_eq!(backwards.severity(5.0), 0.0);
+        assert_eq!(backwards.severity(15.0), 1.0);
+    }
+
+    #[test]
+    fn flying_into_the_floor_does_not_hurt() {
+        let mut app = test_app();
+        spawn_floor(&mut app, 50.0);
+        let player = spawn_player(&mut app, Vec3::new(0.0, FLOOR_TOP + 30.0, 0.0));
+        let mut entity = app.world_mut().entity_mut(player);
+        entity
+            .insert((Health::new(100.0), FallDamage::default()))
+            .remove::<RespawnPoint>();
+        entity.get_mut::<FpsController>().unwrap().move_mode = MoveMode::Noclip;
+        // Straight down as fast as we fly
+        *entity.get_mut::<FpsControllerInput>().unwrap() = FpsControllerInput {
+            movement: Vec3::Z,
+            sprint: true,
+            pitch: -FRAC_PI_2,
+            ..default()
+        };
+        let mut landed = false;
+        for _ in 0..120 {
+            app.update();
+            landed |= !app.world().resource::<Events<Landed>>().is_empty();
+        }
+
+        assert!(landed);
+        assert!(feet_height(&app, player) - FLOOR_TOP < 0.05);
+        assert_eq!(app.world().get::<Health>(player).unwrap().current, 100.0);
     }
 }
@@ -0,0 +1,179 @@
+use super::bindings::Bindings;
+use super::components::*;
+use avian3d::{math::Quaternion, prelude::*};
+use bevy::prelude::*;
+use leafwing_input_manager::prelude::*;
+use std::f32::consts::TAU;
+
+/// Components of a logical player, i.e. the entity that handles the physics computation and
+/// collision. Use [`FpsPlayerBundle::spawn`] to also spawn a camera that renders its view.
+#[derive(Bundle)]
+pub struct FpsPlayerBundle {
+    pub collider: Collider,
+    pub friction: Friction,
+    pub restitution: Restitution,
+    pub velocity: LinearVelocity,
+    pub rigid_body: RigidBody,
+    pub locked_axes: LockedAxes,
+    pub mass: Mass,
+    pub gravity_scale: GravityScale,
+    pub transform: Transform,
+    pub interpolation: TranslationInterpolation,
+    pub shape_caster: ShapeCaster,
+    pub logical_player: LogicalPlayer,
+    pub input: FpsControllerInput,
+    pub controller: FpsController,
+    pub camera_config: CameraConfig,
+    pub input_map: InputMap<FpsActions>,
+    pub look_settings: LookSettings,
+    pub gamepad_look: GamepadLook,
+    pub respawn_point: RespawnPoint,
+}
+
+/// Entities spawned by [`FpsPlayerBundle::spawn`]
+#[derive(Clone, Copy, Debug)]
+pub struct FpsPlayer {
+    pub logical_entity: Entity,
+    pub render_entity: Entity,
+}
+
+impl FpsPlayerBundle {
+    pub fn new(spawn_point: Vec3) -> Self {
+        let controller = FpsController::default();
+        let (yaw, pitch) = (TAU * 5.0 / 8.0, -TAU / 12.0);
+        Self {
+            collider: player_collider(&controller),
+            friction: Friction::ZERO.with_combine_rule(CoefficientCombine::Min),
+            restitution: Restitution::ZERO.with_combine_rule(CoefficientCombine::Min),
+            velocity: LinearVelocity::ZERO,
+            rigid_body: RigidBody::Dynamic,
+            locked_axes: LockedAxes::ROTATION_LOCKED,
+            mass: Mass(1.0),
+            gravity_scale: GravityScale(0.0),
+            transform: Transform::from_translation(spawn_point),
+            // The controller runs at the fixed timestep, smooth out the camera in between ticks
+            interpolation: TranslationInterpolation,
+            shape_caster: ground_caster(&controller),
+            logical_player: LogicalPlayer,
+            input: FpsControllerInput {
+                pitch,
+                yaw,
+                ..default()
+            },
+            controller,
+            camera_config: CameraConfig {
+                height_offset: 0.0,
+                radius_scale: 0.75,
+            },
+            input_map: default_input_map(),
+            look_settings: LookSettings::default(),
+            gamepad_look: GamepadLook::default(),
+            // Until a spawn point or checkpoint says otherwise, respawn where we started
+            respawn_point: RespawnPoint {
+                translation: spawn_point,
+                yaw,
+                pitch,
+            },
+        }
+    }
+
+    /// Replaces the controller settings, resizing the collider and ground cast to match
+    pub fn with_controller(mut self, controller: FpsController) -> Self {
+        self.collider = player_collider(&controller);
+        self.shape_caster = ground_caster(&controller);
+        self.controller = controller;
+        self
+    }
+
+    /// Sets the initial look direction, angles are in radians
+    pub fn with_look(mut self, yaw: f32, pitch: f32) -> Self {
+        self.input.yaw = yaw;
+        self.input.pitch = pitch;
+        self.respawn_point.yaw = yaw;
+        self.respawn_point.pitch = pitch;
+        self
+    }
+
+    pub fn with_camera_config(mut self, camera_config: CameraConfig) -> Self {
+        self.camera_config = camera_config;
+        self
+    }
+
+    pub fn with_look_settings(mut self, look_settings: LookSettings) -> Self {
+        self.look_settings = look_settings;
+        self
+    }
+
+    pub fn with_input_map(mut self, input_map: InputMap<FpsActions>) -> Self {
+        self.input_map = input_map;
+        self
+    }
+
+    /// Plays with `gamepad` alone, using the [`gamepad_input_map`]. Keyboard and mouse
+    /// [`Bindings`] are not applied to it and grabbing the cursor doesn't toggle its input.
+    pub fn with_gamepad(mut self, gamepad: Entity) -> Self {
+        self.input_map = gamepad_input_map().with_gamepad(gamepad);
+        self
+    }
+
+    /// Spawns the logical player along with a "render" player, a camera that follows it and is
+    /// what is displayed to the user
+    pub fn spawn(self, commands: &mut Commands) -> FpsPlayer {
+        let logical_entity = commands.spawn(self).id();
+        let render_entity = commands
+            .spawn((
+                Camera3d::default(),
+                Projection::Perspective(PerspectiveProjection {
+                    fov: TAU / 5.0,
+                    ..default()
+                }),
+                Transform::default(),
+                RenderPlayer { logical_entity },
+            ))
+            .id();
+        FpsPlayer {
+            logical_entity,
+            render_entity,
+        }
+    }
+}
+
+/// Bindings used by [`FpsPlayerBundle::new`], the default keyboard and mouse [`Bindings`]. Use
+/// [`FpsPlayerBundle::with_gamepad`] to play with a gamepad instead.
+pub fn default_input_map() -> InputMap<FpsActions> {
+    Bindings::default().input_map()
+}
+
+/// Gamepad bindings: the left stick moves and the right stick looks around, see [`GamepadLook`].
+/// South jumps, east crouches, the left bumper sprints and the right bumper toggles flying.
+pub fn gamepad_input_map() -> InputMap<FpsActions> {
+    InputMap::default()
+        .with_dual_axis(
+            FpsActions::Move,
+            GamepadStick::LEFT.with_circle_deadzone(0.1),
+        )
+        .with_dual_axis(FpsActions::Look, GamepadStick::RIGHT)
+        .with(FpsActions::Jump, GamepadButton::South)
+        .with(FpsActions::Crouch, GamepadButton::East)
+        .with(FpsActions::Sprint, GamepadButton::LeftTrigger)
+        .with(FpsActions::Fly, GamepadButton::RightTrigger)
+}
+
+fn player_collider(controller: &FpsController) -> Collider {
+    Collider::capsule(controller.radius, controller.height)
+}
+
+fn ground_caster(controller: &FpsController) -> ShapeCaster {
+    // Capsule cast downwards to find ground
+    // Better than a ray cast as it handles when you are near the edge of a surface
+    // Sized for crouching so that it never starts out inside the ground
+    let mut cast_capsule = Collider::capsule(controller.radius, controller.crouch_height);
+    cast_capsule.set_scale(Vec3::ONE * 0.99, 10);
+    // The cast starts from the center of the player and reaches a bit below the feet when standing
+    let cast_bottom = (controller.crouch_height / 2.0 + controller.radius) * 0.99;
+    let standing_feet = controller.upright_height / 2.0 + controller.radius;
+    ShapeCaster::new(cast_capsule, Vec3::ZERO, Quaternion::default(), Dir3::NEG_Y)
+        .with_query_filter(SpatialQueryFilter::from_mask(!VOLUME_LAYERS))
+        .with_max_hits(10)
+        .with_max_distance(standing_feet - cast_bottom + 0.75)
+}
@@ -1,19 +1,86 @@
+use super::bindings::*;
+use super::components::*;
+use super::demo::*;
+use super::events::*;
+use super::ghost::*;
 use super::input::*;
+use super::level::*;
 use super::movement::*;
 use super::render::*;
+use super::respawn::*;
+use super::util::manage_cursor;
 
 use bevy::prelude::*;
+use leafwing_input_manager::prelude::*;
+use std::path::PathBuf;
 
-pub struct FpsControllerPlugin;
+pub struct FpsControllerPlugin {
+    /// Registers the `InputManagerPlugin` for [`FpsActions`], disable this if the app already
+    /// adds it
+    pub input_manager: bool,
+    /// Grabs the cursor on left click and releases it on escape
+    pub manage_cursor: bool,
+    /// Gives every render player its own viewport of the primary window
+    pub split_screen: bool,
+}
+
+impl Default for FpsControllerPlugin {
+    fn default() -> Self {
+        Self {
+            input_manager: true,
+            manage_cursor: true,
+            split_screen: true,
+        }
+    }
+}
 
 impl Plugin for FpsControllerPlugin {
     fn build(&self, app: &mut App) {
+        app.add_event::<Jumped>()
+            .add_event::<Landed>()
+            .add_event::<LeftGround>()
+            .add_event::<StartedCrouch>()
+            .add_event::<StoppedCrouch>()
+            .add_event::<ModeChanged>()
+            .add_event::<Died>()
+            .add_event::<PlayerRespawned>()
+            .add_event::<DemoFinished>()
+            .init_resource::<KillPlane>();
+
+        if self.input_manager {
+            app.add_plugins(InputManagerPlugin::<FpsActions>::default());
+        }
+        if self.manage_cursor {
+            app.add_systems(Update, manage_cursor);
+        }
+        if self.split_screen {
+            app.add_systems(Update, fps_controller_viewports);
+        }
+
+        // Movement is simulated at the fixed timestep, in step with physics, so that it behaves
+        // the same at any frame rate. Looking around and the camera follow the rendered frame.
         app.add_systems(
-            Update,
+            FixedUpdate,
             (
                 fps_controller_grounded,
+                fps_controller_fall_damage,
                 fps_controller_input,
+                fps_controller_playback,
+                fps_controller_record,
                 fps_controller_move,
+                fps_controller_cue_playback,
+                fps_controller_spawn_points,
+                fps_controller_checkpoints,
+                fps_controller_kill,
+                fps_controller_respawn,
+            )
+                .chain(),
+        )
+        .add_systems(
+            Update,
+            (
+                fps_controller_look_settings,
+                fps_controller_look_input,
                 fps_controller_look,
                 fps_controller_render,
             )
@@ -21,3 +88,104 @@ impl Plugin for FpsControllerPlugin {
         );
     }
 }
+
+/// Loads levels from the [`LevelRegistry`] with [`LoadLevel`] events, tracking their progress in
+/// the [`LevelState`]. Add the registry as a resource, e.g. with [`LevelRegistry::from_ron`].
+/// Builds the physics of the [`MainScene`], rebuilding it when its glTF changes.
+pub struct LevelPlugin;
+
+impl Plugin for LevelPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<LoadLevel>()
+            .add_event::<LevelCompleted>()
+            .init_resource::<LevelRegistry>()
+            .init_state::<LevelState>()
+            .add_observer(level_scene_ready)
+            .add_systems(
+                Update,
+                (
+                    load_level,
+                    scene_colliders.run_if(resource_exists::<MainScene>),
+                    level_loaded.run_if(in_state(LevelState::Loading)),
+                )
+                    .chain(),
+            )
+            .add_systems(
+                FixedUpdate,
+                (
+                    fps_controller_level_exit
+                        .run_if(in_state(LevelState::Playing).and(resource_exists::<MainScene>)),
+                    // Last, so that nothing moves the players it holds in place during a reload
+                    fps_controller_level_reload.after(fps_controller_respawn),
+                ),
+            );
+    }
+}
+
+/// Loads the [`Bindings`] of keyboard and mouse players from a file, and lets them be rebound with
+/// [`StartRebind`] events. Rebinding saves them back to the file.
+pub struct BindingsPlugin {
+    pub path: PathBuf,
+}
+
+impl Default for BindingsPlugin {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("config/bindings.ron"),
+        }
+    }
+}
+
+impl Plugin for BindingsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<StartRebind>()
+            .add_event::<Rebound>()
+            .insert_resource(Bindings::load(&self.path))
+            .insert_resource(BindingsFile(self.path.clone()))
+            .init_resource::<Rebinding>()
+            .add_systems(
+                Update,
+                (listen_for_rebind, save_bindings, apply_bindings).chain(),
+            );
+    }
+}
+
+/// Records the players' runs through the levels of the [`LevelPlugin`], replaying the best run of
+/// the current level as a translucent ghost. Best runs are saved in `directory` and loaded when
+/// their level starts.
+pub struct GhostPlugin {
+    pub directory: PathBuf,
+}
+
+impl Default for GhostPlugin {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("config/ghosts"),
+        }
+    }
+}
+
+impl Plugin for GhostPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(GhostDirectory(self.directory.clone()))
+            .init_resource::<BestGhost>()
+            .add_systems(
+                OnEnter(LevelState::Playing),
+                start_ghost_runs.run_if(resource_exists::<MainScene>),
+            )
+            .add_systems(OnExit(LevelState::Playing), end_ghost_runs)
+            .add_systems(
+                FixedUpdate,
+                (record_ghost_runs, move_ghosts, finish_ghost_runs)
+                    .chain()
+                    .after(fps_controller_move)
+                    .after(fps_controller_level_exit)
+                    .run_if(in_state(LevelState::Playing)),
+            )
+            .add_systems(
+                Update,
+                // Without rendering ghosts are still there, only invisible
+                ghost_visuals.run_if(resource_exists::<Assets<StandardMaterial>>),
+            );
+    }
+}
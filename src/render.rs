@@ -1,6 +1,6 @@
 use super::components::*;
 use avian3d::prelude::Collider;
-use bevy::prelude::*;
+use bevy::{prelude::*, render::camera::Viewport, window::PrimaryWindow};
 
 // Type alias to reduce complexity
 type LogicalPlayerQuery<'w, 's> = Query<
@@ -31,3 +31,45 @@ pub fn fps_controller_render(
         }
     }
 }
+
+/// Splits the primary window into a grid with one viewport per render player, ordered by their
+/// camera [`Entity`]. That is usually the order they were spawned in, but not once entities of
+/// despawned players are reused. A single player gets the whole window.
+pub fn fps_controller_viewports(
+    window_query: Query<&Window, With<PrimaryWindow>>,
+    mut camera_query: Query<(Entity, &mut Camera), With<RenderPlayer>>,
+) {
+    let Ok(window) = window_query.single() else {
+        return;
+    };
+    let mut cameras = camera_query.iter_mut().collect::<Vec<_>>();
+    cameras.sort_by_key(|(entity, _)| *entity);
+
+    let count = cameras.len() as u32;
+    let columns = (count as f32).sqrt().ceil() as u32;
+    let rows = count.div_ceil(columns.max(1));
+    let window_size = window.physical_size();
+    let size = window_size / UVec2::new(columns.max(1), rows.max(1));
+
+    for (index, (_, mut camera)) in cameras.into_iter().enumerate() {
+        let index = index as u32;
+        let rect = (count > 1 && size.cmpgt(UVec2::ZERO).all())
+            .then(|| (UVec2::new(index % columns, index / columns) * size, size));
+        let current = camera
+            .viewport
+            .as_ref()
+            .map(|viewport| (viewport.physical_position, viewport.physical_size));
+        // Avoid triggering change detection every frame
+        if current != rect {
+            camera.viewport = rect.map(|(physical_position, physical_size)| Viewport {
+                physical_position,
+                physical_size,
+                ..default()
+            });
+        }
+        let order = index as isize;
+        if camera.order != order {
+            camera.order = order;
+        }
+    }
+}
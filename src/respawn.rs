@@ -0,0 +1,389 @@
+use super::components::*;
+use super::events::*;
+use super::level::LevelReload;
+use avian3d::prelude::*;
+use bevy::prelude::*;
+use bevy_transform_interpolation::TranslationEasingState;
+
+type RespawnQuery<'w, 's> = Query<
+    'w,
+    's,
+    (
+        &'static RespawnPoint,
+        &'static mut FpsController,
+        &'static mut FpsControllerState,
+        &'static mut FpsControllerInput,
+        &'static mut Collider,
+        &'static mut Transform,
+        &'static mut LinearVelocity,
+        Option<&'static mut Health>,
+        Option<&'static mut TranslationEasingState>,
+    ),
+    With<LogicalPlayer>,
+>;
+
+/// Makes a newly placed [`SpawnPoint`], e.g. from a level that just loaded, the respawn point of
+/// every player. Players keep the checkpoints they reached when their level only reloads.
+pub fn fps_controller_spawn_points(
+    spawn_points: Query<&GlobalTransform, (With<SpawnPoint>, Changed<GlobalTransform>)>,
+    mut players: Query<(&mut RespawnPoint, Option<&LevelReload>), With<LogicalPlayer>>,
+) {
+    let Some(spawn_point) = spawn_points.iter().next() else {
+        return;
+    };
+    for (mut respawn_point, reload) in &mut players {
+        if reload.is_some_and(|reload| !reload.to_spawn_point) {
+            continue;
+        }
+        *respawn_point = RespawnPoint::from_transform(&spawn_point.compute_transform());
+    }
+}
+
+/// Players touching a [`Checkpoint`] respawn at it from then on.
+pub fn fps_controller_checkpoints(
+    spatial_query: Res<SpatialQueryPipeline>,
+    mut players: Query<(&Collider, &Transform, &mut RespawnPoint), With<LogicalPlayer>>,
+    checkpoints: Query<&GlobalTransform, With<Checkpoint>>,
+) {
+    let filter = SpatialQueryFilter::from_mask(CHECKPOINT_LAYER);
+    for (collider, transform, mut respawn_point) in &mut players {
+        let checkpoint = spatial_query
+            .shape_intersections(collider, transform.translation, transform.rotation, &filter)
+            .into_iter()
+            .find_map(|checkpoint| checkpoints.get(checkpoint).ok());
+        if let Some(checkpoint) = checkpoint {
+            respawn_point.set_if_neq(RespawnPoint::from_transform(
+                &checkpoint.compute_transform(),
+            ));
+        }
+    }
+}
+
+/// Kills players that fall below the [`KillPlane`] or touch a [`KillVolume`].
+pub fn fps_controller_kill(
+    mut commands: Commands,
+    spatial_query: Res<SpatialQueryPipeline>,
+    kill_plane: Res<KillPlane>,
+    mut players: Query<(Entity, &Collider, &Transform, Option<&mut Health>), With<LogicalPlayer>>,
+) {
+    let filter = SpatialQueryFilter::from_mask(KILL_LAYER);
+    for (entity, collider, transform, health) in &mut players {
+        let killed = transform.translation.y < kill_plane.0
+            || !spatial_query
+                .shape_intersections(collider, transform.translation, transform.rotation, &filter)
+                .is_empty();
+        if !killed {
+            continue;
+        }
+        if let Some(mut health) = health {
+            // Already died this tick, e.g. from fall damage
+            if health.is_dead() {
+                continue;
+            }
+            health.current = 0.0;
+        }
+        commands.send_event(Died { entity });
+    }
+}
+
+/// Moves players that died back to their [`RespawnPoint`], restoring their look direction and
+/// health. They come back standing on foot, out of whatever slide, crouch, wall run, mantle or
+/// other mode they died in.
+pub fn fps_controller_respawn(
+    mut commands: Commands,
+    mut died: EventReader<Died>,
+    mut players: RespawnQuery,
+) {
+    for &Died { entity } in died.read() {
+        let Ok((
+            respawn_point,
+            mut controller,
+            mut state,
+            mut input,
+            mut collider,
+            mut transform,
+            mut velocity,
+            health,
+            easing,
+        )) = players.get_mut(entity)
+        else {
+            continue;
+        };
+        transform.translation = respawn_point.translation;
+        *velocity = LinearVelocity::ZERO;
+        input.yaw = respawn_point.yaw;
+        input.pitch = respawn_point.pitch;
+        // Timers still running would carry on sliding, mantling, etc. from where we died
+        *state = FpsControllerState::default();
+        controller.ground_tick = 0;
+        controller.height = controller.upright_height;
+        collider.set_shape(
+            Collider::capsule(controller.radius, controller.height)
+                .shape()
+                .clone(),
+        );
+        commands.entity(entity).remove::<(Sliding, CrouchBlocked)>();
+        if controller.move_mode != MoveMode::Ground {
+            controller.move_mode = MoveMode::Ground;
+            commands.send_event(ModeChanged {
+                entity,
+                mode: MoveMode::Ground,
+            });
+        }
+        // Otherwise the render transform is smoothed across the map from where we died
+        if let Some(mut easing) = easing {
+            easing.start = Some(respawn_point.translation);
+        }
+        if let Some(mut health) = health {
+            health.current = health.max;
+        }
+        commands.send_event(PlayerRespawned { entity });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{run, spawn_box, spawn_floor, spawn_player, test_app, FLOOR_TOP};
+
+    #[derive(Resource, Default)]
+    struct Respawns(Vec<Entity>);
+
+    fn respawn_app() -> App {
+        let mut app = test_app();
+        app.init_resource::<Respawns>().add_systems(
+            Update,
+            |mut respawned: EventReader<PlayerRespawned>, mut respawns: ResMut<Respawns>| {
+                respawns
+                    .0
+                    .extend(respawned.read().map(|event| event.entity));
+            },
+        );
+        app
+    }
+
+    fn spawn_volume(app: &mut App, min: Vec3, max: Vec3, layer: LayerMask, marker: impl Bundle) {
+        let size = max - min;
+        app.world_mut().spawn((
+            Collider::cuboid(size.x, size.y, size.z),
+            Sensor,
+            CollisionLayers::new(layer, LayerMask::ALL),
+            Transform::from_translation((min + max) / 2.0),
+            marker,
+        ));
+    }
+
+    #[test]
+    fn respawns_players_below_kill_plane_at_spawn_point() {
+        let mut app = respawn_app();
+        app.insert_resource(KillPlane(-10.0));
+        spawn_floor(&mut app, 5.0);
+        app.world_mut().spawn((
+            SpawnPoint,
+            Transform::from_xyz(2.0, FLOOR_TOP + 1.25, 3.0).with_rotation(Quat::from_euler(
+                EulerRot::YXZ,
+                1.0,
+                -0.5,
+                0.0,
+            )),
+        ));
+        // Off the edge of the floor, nothing below to land on
+        let player = spawn_player(&mut app, Vec3::new(20.0, FLOOR_TOP + 1.25, 0.0));
+        let crate_ = app
+            .world_mut()
+            .spawn((
+                Collider::cuboid(1.0, 1.0, 1.0),
+                RigidBody::Dynamic,
+                Transform::from_xyz(-20.0, FLOOR_TOP, 0.0),
+            ))
+            .id();
+        run(&mut app, 2.0);
+
+        assert_eq!(app.world().resource::<Respawns>().0, [player]);
+        let world = app.world();
+        // Standing on the floor at the spawn point
+        let translation = world.get::<Position>(player).unwrap().0;
+        assert!(
+            translation.xz().distance(Vec2::new(2.0, 3.0)) < 0.1
+                && (translation.y - FLOOR_TOP - 1.5).abs() < 0.1,
+            "respawned at {translation}"
+        );
+        let input = world.get::<FpsControllerInput>(player).unwrap();
+        assert!((input.yaw - 1.0).abs() < 1e-4 && (input.pitch + 0.5).abs() < 1e-4);
+        // Only players respawn
+        assert!(world.get::<Position>(crate_).unwrap().y < -10.0);
+    }
+
+    #[test]
+    fn respawns_at_last_checkpoint_after_kill_volume() {
+        let mut app = respawn_app();
+        spawn_box(
+            &mut app,
+            Vec3::new(-5.0, FLOOR_TOP - 1.0, -50.0),
+            Vec3::new(5.0, FLOOR_TOP, 5.0),
+        );
+        // Walking forward goes through a checkpoint and then into a kill volume
+        spawn_volume(
+            &mut app,
+            Vec3::new(-5.0, FLOOR_TOP, -6.0),
+            Vec3::new(5.0, FLOOR_TOP + 3.0, -4.0),
+            CHECKPOINT_LAYER,
+            Checkpoint,
+        );
+        spawn_volume(
+            &mut app,
+            Vec3::new(-5.0, FLOOR_TOP, -16.0),
+            Vec3::new(5.0, FLOOR_TOP + 3.0, -14.0),
+            KILL_LAYER,
+            KillVolume,
+        );
+        let player = spawn_player(&mut app, Vec3::new(0.0, FLOOR_TOP + 1.25, 0.0));
+        app.world_mut()
+            .entity_mut(player)
+            .insert(Health::new(100.0));
+        run(&mut app, 0.5);
+        app.world_mut()
+            .get_mut::<FpsControllerInput>(player)
+            .unwrap()
+            .movement = Vec3::Z;
+        run(&mut app, 2.0);
+
+        assert_eq!(app.world().resource::<Respawns>().0, [player]);
+        let world = app.world();
+        // Respawned at the checkpoint and walked on from there
+        let translation = world.get::<Position>(player).unwrap().0;
+        assert!(
+            translation.z < -5.0 && translation.z > -14.0,
+            "at {translation}"
+        );
+        assert_eq!(
+            world.get::<RespawnPoint>(player).unwrap().translation,
+            Vec3::new(0.0, FLOOR_TOP + 1.5, -5.0)
+        );
+        assert_eq!(world.get::<Health>(player).unwrap().current, 100.0);
+    }
+
+    /// Where a player is, how fast it moves and how healthy it is right after respawning
+    #[derive(Resource, Default)]
+    struct Respawned(Option<(Vec3, Vec3, Health)>);
+
+    fn record_respawned(
+        mut respawned: EventReader<PlayerRespawned>,
+        players: Query<(&Transform, &LinearVelocity, &Health)>,
+        mut state: ResMut<Respawned>,
+    ) {
+        for event in respawned.read() {
+            let (transform, velocity, health) = players.get(event.entity).unwrap();
+            state.0 = Some((transform.translation, velocity.0, *health));
+        }
+    }
+
+    #[test]
+    fn lethal_fall_respawns_player() {
+        let mut app = test_app();
+        app.init_resource::<Respawned>()
+            .add_systems(FixedUpdate, record_respawned.after(fps_controller_respawn));
+        spawn_floor(&mut app, 50.0);
+        let player = spawn_player(&mut app, Vec3::new(0.0, FLOOR_TOP + 26.25, 0.0));
+        let respawn_point = Vec3::new(5.0, FLOOR_TOP + 1.5, 5.0);
+        app.world_mut().entity_mut(player).insert((
+            Health::new(100.0),
+            FallDamage::default(),
+            RespawnPoint {
+                translation: respawn_point,
+                yaw: 0.0,
+                pitch: 0.0,
+            },
+        ));
+        run(&mut app, 3.0);
+
+        let (translation, velocity, health) = app
+            .world()
+            .resource::<Respawned>()
+            .0
+            .expect("never respawned");
+        assert_eq!(translation, respawn_point);
+        assert_eq!(velocity, Vec3::ZERO);
+        assert_eq!(health.current, health.max);
+        // and standing there safely afterwards
+        let world = app.world();
+        let position = world.get::<Position>(player).unwrap().0;
+        assert!(
+            position.xz().distance(respawn_point.xz()) < 0.1,
+            "at {position}"
+        );
+        assert_eq!(world.get::<Health>(player).unwrap().current, 100.0);
+    }
+
+    /// The controller a player respawned with, and where its render transform eases in from
+    #[derive(Resource, Default)]
+    struct RespawnedController(Option<(MoveMode, f32, FpsControllerState, bool, Option<Vec3>)>);
+
+    fn record_respawned_controller(
+        mut respawned: EventReader<PlayerRespawned>,
+        players: Query<(
+            &FpsController,
+            &FpsControllerState,
+            Has<Sliding>,
+            &TranslationEasingState,
+        )>,
+        mut state: ResMut<RespawnedController>,
+    ) {
+        for event in respawned.read() {
+            let (controller, controller_state, sliding, easing) =
+                players.get(event.entity).unwrap();
+            state.0 = Some((
+                controller.move_mode,
+                controller.height,
+                *controller_state,
+                sliding,
+                easing.start,
+            ));
+        }
+    }
+
+    #[test]
+    fn respawns_standing_on_foot() {
+        let mut app = test_app();
+        app.init_resource::<RespawnedController>().add_systems(
+            FixedUpdate,
+            record_respawned_controller.after(fps_controller_respawn),
+        );
+        spawn_floor(&mut app, 50.0);
+        let player = spawn_player(&mut app, Vec3::new(0.0, FLOOR_TOP + 1.25, 0.0));
+        run(&mut app, 0.5);
+        let respawn_point = Vec3::new(20.0, FLOOR_TOP + 1.5, 20.0);
+        let mut entity = app.world_mut().entity_mut(player);
+        entity.insert((
+            Sliding,
+            RespawnPoint {
+                translation: respawn_point,
+                yaw: 0.0,
+                pitch: 0.0,
+            },
+        ));
+        let mut controller = entity.get_mut::<FpsController>().unwrap();
+        controller.move_mode = MoveMode::Noclip;
+        controller.height = controller.crouch_height;
+        *entity.get_mut::<FpsControllerState>().unwrap() = FpsControllerState {
+            crouched: true,
+            ladder_cooldown: 0.5,
+            slide_timer: 0.5,
+            wall_run_timer: 1.0,
+            ..default()
+        };
+        app.world_mut().send_event(Died { entity: player });
+        run(&mut app, 0.1);
+
+        let (move_mode, height, state, sliding, easing_start) = app
+            .world()
+            .resource::<RespawnedController>()
+            .0
+            .expect("never respawned");
+        assert_eq!(move_mode, MoveMode::Ground);
+        assert_eq!(height, FpsController::default().upright_height);
+        assert_eq!(state, FpsControllerState::default());
+        assert!(!sliding);
+        assert_eq!(easing_start, Some(respawn_point));
+    }
+}
@@ -0,0 +1,74 @@
+//! Helpers shared by the tests of the different modules
+
+use crate::{FpsController, FpsControllerPlugin, FpsPlayerBundle};
+use avian3d::prelude::*;
+use bevy::{prelude::*, time::TimeUpdateStrategy};
+use std::time::Duration;
+
+pub(crate) const FLOOR_TOP: f32 = 0.0;
+
+pub(crate) fn test_app() -> App {
+    test_app_with(())
+}
+
+/// [`test_app`] with some more plugins, which need to be added before it is finished
+pub(crate) fn test_app_with<M>(plugins: impl bevy::app::Plugins<M>) -> App {
+    let mut app = App::new();
+    app.add_plugins((
+        MinimalPlugins,
+        TransformPlugin,
+        PhysicsPlugins::default(),
+        bevy::asset::AssetPlugin::default(),
+        bevy::scene::ScenePlugin,
+        FpsControllerPlugin {
+            input_manager: false,
+            manage_cursor: false,
+            split_screen: false,
+        },
+    ))
+    .add_plugins(plugins)
+    .init_resource::<Assets<Mesh>>()
+    .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f32(
+        1.0 / 60.0,
+    )));
+    app.finish();
+    app
+}
+
+pub(crate) fn spawn_box(app: &mut App, min: Vec3, max: Vec3) {
+    let size = max - min;
+    app.world_mut().spawn((
+        Collider::cuboid(size.x, size.y, size.z),
+        RigidBody::Static,
+        Transform::from_translation((min + max) / 2.0),
+    ));
+}
+
+/// A square floor reaching `half_size` out from the origin, with its top at [`FLOOR_TOP`]
+pub(crate) fn spawn_floor(app: &mut App, half_size: f32) {
+    spawn_box(
+        app,
+        Vec3::new(-half_size, FLOOR_TOP - 1.0, -half_size),
+        Vec3::new(half_size, FLOOR_TOP, half_size),
+    );
+}
+
+pub(crate) fn spawn_player(app: &mut App, position: Vec3) -> Entity {
+    app.world_mut()
+        .spawn(
+            FpsPlayerBundle::new(position)
+                .with_controller(FpsController {
+                    // Inputs are set by the tests directly
+                    enable_input: false,
+                    ..default()
+                })
+                .with_look(0.0, 0.0),
+        )
+        .id()
+}
+
+pub(crate) fn run(app: &mut App, seconds: f32) {
+    for _ in 0..(seconds * 60.0) as usize {
+        app.update();
+    }
+}
@@ -1,52 +1,71 @@
-use crate::{FpsController, LogicalPlayer};
+use crate::{DebugText, FpsActions, FpsController, LogicalPlayer, VOLUME_LAYERS};
 use avian3d::prelude::*;
 use bevy::{
-    gltf::{Gltf, GltfMesh, GltfNode},
     prelude::*,
-    window::CursorGrabMode,
+    window::{CursorGrabMode, PrimaryWindow},
 };
+use leafwing_input_manager::prelude::*;
+use std::{fs, io, path::Path};
 
-use super::components::MainScene;
+/// Filter for the spatial queries of a controller, ignoring the controller itself and volumes
+/// such as water.
+pub fn controller_filter(entity: Entity) -> SpatialQueryFilter {
+    SpatialQueryFilter::from_mask(!VOLUME_LAYERS).with_excluded_entities([entity])
+}
+
+/// Finds the component of `velocity` that would carry a controller standing at `feet` over a
+/// ledge higher than `max_drop`.
+pub fn overhang_component(
+    entity: Entity,
+    spatial_query: &SpatialQueryPipeline,
+    feet: Vec3,
+    velocity: Vec3,
+    max_drop: f32,
+    reach: f32,
+    dt: f32,
+) -> Option<Vec3> {
+    let lateral_velocity = Vec3::new(velocity.x, 0.0, velocity.z);
+    let back = Dir3::new(-lateral_velocity).ok()?;
+    let filter = controller_filter(entity);
+    // Keep a little distance from the edge so that we never end up balancing on it
+    let future_feet = feet + lateral_velocity * dt - back * 0.125;
 
-// pub fn overhang_component(
-//     entity: Entity,
-//     transform: &Transform,
-//     physics_context: &RapierContext,
-//     velocity: Vec3,
-//     dt: f32,
-// ) -> Option<Vec3> {
-//   // Cast a segment (zero radius on capsule) from our next position back towards us
-//     // If there is a ledge in front of us we will hit the edge of it
-//     // We can use the normal of the hit to subtract off the component that is overhanging
-//     let cast_capsule = Collider::capsule(Vec3::Y * 0.125, -Vec3::Y * 0.125, 0.0);
-//     let filter = QueryFilter::default().exclude_rigid_body(entity);
-//     let future_position = transform.translation + velocity * dt;
-//     let cast = physics_context.cast_shape(
-//         future_position,
-//         transform.rotation,
-//         -velocity,
-//         &cast_capsule,
-//         0.5,
-//         true,
-//         filter,
-//     );
-//     if let Some((_, toi_details)) = toi_details_unwrap(cast) {
-//         let cast = physics_context.cast_ray(
-//             future_position + Vec3::Y * 0.125,
-//             -Vec3::Y,
-//             0.375,
-//             false,
-//             filter,
-//         );
-//         // Make sure that this is actually a ledge, e.g. there is no ground in front of us
-//         if cast.is_none() {
-//             let normal = -toi_details.normal1;
-//             let alignment = Vec3::dot(velocity, normal);
-//             return Some(alignment * normal);
-//         }
-//     }
-//     None
-// }
+    // Make sure that this is actually a ledge, e.g. there is no ground in front of us
+    let ground = spatial_query.cast_ray(
+        future_feet + Vec3::Y * 0.125,
+        Dir3::NEG_Y,
+        0.125 + max_drop,
+        false,
+        &filter,
+    );
+    if ground.is_some() {
+        return None;
+    }
+
+    // Cast a segment (zero radius on capsule) from our next position back towards us
+    // If there is a ledge in front of us we will hit the edge of it
+    // We can use the normal of the hit to subtract off the component that is overhanging
+    let cast_capsule = Collider::capsule(0.0, 0.25);
+    let cast = spatial_query.cast_shape(
+        &cast_capsule,
+        future_feet - Vec3::Y * 0.25,
+        Quat::IDENTITY,
+        back,
+        &ShapeCastConfig::from_max_distance(lateral_velocity.length() * dt + 0.125 + reach),
+        &filter,
+    );
+    let Some(hit) = cast else {
+        // No edge to slide along, all of our lateral movement is overhanging
+        return Some(lateral_velocity);
+    };
+    let normal = Vec3::new(hit.normal1.x, 0.0, hit.normal1.z).normalize_or_zero();
+    let alignment = Vec3::dot(lateral_velocity, normal);
+    if alignment > 0.0 {
+        Some(alignment * normal)
+    } else {
+        None
+    }
+}
 
 pub fn acceleration(
     wish_direction: Vec3,
@@ -65,9 +84,35 @@ pub fn acceleration(
     wish_direction * acceleration_speed
 }
 
+/// Removes the part of `velocity` going into a surface, keeping all of the speed along it
+pub fn clip_velocity(velocity: Vec3, normal: Vec3) -> Vec3 {
+    let into_surface = Vec3::dot(velocity, normal);
+    if into_surface < 0.0 {
+        velocity - into_surface * normal
+    } else {
+        velocity
+    }
+}
+
+/// Writes `contents` to `path`, creating its directory first if needed, e.g. on the first save
+/// into a fresh user directory
+pub(crate) fn write_file(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)?;
+    }
+    fs::write(path, contents)
+}
+
+/// An error for a file that can't be read because it isn't what we expected
+pub(crate) fn invalid_data(
+    error: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
 pub fn display_text(
     mut controller_query: Query<(&Transform, &LinearVelocity), With<LogicalPlayer>>,
-    mut text_query: Query<&mut Text>,
+    mut text_query: Query<&mut Text, With<DebugText>>,
 ) {
     for (transform, velocity) in &mut controller_query {
         for mut text in &mut text_query {
@@ -85,62 +130,33 @@ pub fn display_text(
     }
 }
 
-pub fn scene_colliders(
-    mut commands: Commands,
-    mut main_scene: ResMut<MainScene>,
-    gltf_assets: Res<Assets<Gltf>>,
-    gltf_mesh_assets: Res<Assets<GltfMesh>>,
-    gltf_node_assets: Res<Assets<GltfNode>>,
-    mesh_assets: Res<Assets<Mesh>>,
-) {
-    if main_scene.is_loaded {
-        return;
-    }
-
-    let gltf = gltf_assets.get(&main_scene.handle);
-
-    if let Some(gltf) = gltf {
-        let scene = gltf.scenes.first().unwrap().clone();
-        commands.spawn(SceneRoot(scene));
-        for node in &gltf.nodes {
-            let node = gltf_node_assets.get(node).unwrap();
-            if let Some(gltf_mesh) = node.mesh.clone() {
-                let gltf_mesh = gltf_mesh_assets.get(&gltf_mesh).unwrap();
-                for mesh_primitive in &gltf_mesh.primitives {
-                    let mesh = mesh_assets.get(&mesh_primitive.mesh).unwrap();
-                    commands.spawn((
-                        Collider::trimesh_from_mesh(mesh).unwrap(),
-                        RigidBody::Static,
-                        node.transform,
-                    ));
-                }
-            }
-        }
-        main_scene.is_loaded = true;
-    }
-}
-
+/// Grabs the cursor on left click and releases it on escape, enabling the input of the controllers
+/// that are played with keyboard and mouse. Controllers bound to a specific gamepad, or without an
+/// input map at all, are left alone.
 pub fn manage_cursor(
     btn: Res<ButtonInput<MouseButton>>,
     key: Res<ButtonInput<KeyCode>>,
-    mut window_query: Query<&mut Window>,
-    mut controller_query: Query<&mut FpsController>,
+    mut window_query: Query<&mut Window, With<PrimaryWindow>>,
+    mut controller_query: Query<(&mut FpsController, &InputMap<FpsActions>)>,
 ) {
     let Ok(mut window) = window_query.single_mut() else {
         return;
     };
+    let mut set_enable_input = |enable_input: bool| {
+        for (mut controller, input_map) in &mut controller_query {
+            if input_map.gamepad().is_none() {
+                controller.enable_input = enable_input;
+            }
+        }
+    };
     if btn.just_pressed(MouseButton::Left) {
         window.cursor_options.grab_mode = CursorGrabMode::Locked;
         window.cursor_options.visible = false;
-        for mut controller in &mut controller_query {
-            controller.enable_input = true;
-        }
+        set_enable_input(true);
     }
     if key.just_pressed(KeyCode::Escape) {
         window.cursor_options.grab_mode = CursorGrabMode::None;
         window.cursor_options.visible = true;
-        for mut controller in &mut controller_query {
-            controller.enable_input = false;
-        }
+        set_enable_input(false);
     }
 }